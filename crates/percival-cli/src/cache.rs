@@ -0,0 +1,286 @@
+//! Offline cache and lockfile for reproducible imports.
+//!
+//! Remote imports are downloaded once and stored in a local cache, keyed by
+//! their URI and the SHA-256 hash of their contents. The lockfile records the
+//! hash of each import, so later runs can load the same data from the cache.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use sha2::{Digest, Sha256};
+
+use percival::resolve;
+
+/// How imports are resolved against the lockfile.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Download every import again, and record the new hashes.
+    Update,
+    /// Use the hashes in the lockfile, only downloading imports that are
+    /// missing from the cache or not yet locked.
+    Locked,
+    /// Only load imports from the cache, failing if any are not locked.
+    Frozen,
+}
+
+/// Cache of imported datasets, with the hashes they are locked to.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    mode: Mode,
+    locked: BTreeMap<String, String>,
+    resolved: RefCell<BTreeMap<String, String>>,
+}
+
+impl Cache {
+    /// Create a new cache in a directory, with the contents of a lockfile.
+    pub fn new(dir: PathBuf, mode: Mode, lockfile: &str) -> Result<Self, String> {
+        Ok(Self {
+            dir,
+            mode,
+            locked: parse_lockfile(lockfile)?,
+            resolved: RefCell::new(BTreeMap::new()),
+        })
+    }
+
+    /// Return how imports are resolved against the lockfile.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Resolve an import to a data URL with its cached, verified contents.
+    pub fn resolve(&self, uri: &str) -> Result<String, String> {
+        let locked =
+            match self.mode {
+                Mode::Update => None,
+                Mode::Locked => self.locked.get(uri),
+                Mode::Frozen => Some(self.locked.get(uri).ok_or_else(|| {
+                    "not found in lockfile, run with --lock to add it".to_string()
+                })?),
+            };
+
+        let (hash, data) = match locked {
+            Some(hash) => match fs::read(self.entry(uri, hash)) {
+                Ok(data) => (hash.clone(), data),
+                Err(_) if self.mode == Mode::Frozen => {
+                    return Err(format!("sha256 {} is missing from the cache", hash));
+                }
+                Err(_) => self.fetch(uri)?,
+            },
+            None => self.fetch(uri)?,
+        };
+
+        let actual = sha256(&data);
+        if actual != hash {
+            return Err(format!(
+                "content hash mismatch, expected sha256 {} but found {}",
+                hash, actual,
+            ));
+        }
+        if let Some(expected) = locked.filter(|expected| **expected != actual) {
+            return Err(format!(
+                "content changed since it was locked, expected sha256 {} but found {}",
+                expected, actual,
+            ));
+        }
+
+        self.resolved.borrow_mut().insert(uri.into(), hash);
+        Ok(data_url(uri, &data))
+    }
+
+    /// Return the contents of the lockfile for the imports resolved so far.
+    pub fn lockfile(&self) -> String {
+        let mut lockfile = String::from("# Import lockfile generated by Percival. Do not edit.\n");
+        for (uri, hash) in self.resolved.borrow().iter() {
+            lockfile += &format!("{} {}\n", uri, hash);
+        }
+        lockfile
+    }
+
+    /// Download an import and store it in the cache, returning its hash.
+    fn fetch(&self, uri: &str) -> Result<(String, Vec<u8>), String> {
+        let url = resolve::builtin(uri).ok_or_else(|| "cannot be cached".to_string())?;
+        let output = Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--location", &url])
+            .output()
+            .map_err(|err| format!("failed to run curl: {}", err))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("failed to fetch {}: {}", url, stderr.trim()));
+        }
+
+        let hash = sha256(&output.stdout);
+        let path = self.entry(uri, &hash);
+        fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&path, &output.stdout))
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok((hash, output.stdout))
+    }
+
+    /// Path of the cache entry for an import with the given content hash.
+    fn entry(&self, uri: &str, hash: &str) -> PathBuf {
+        self.dir.join(sha256(uri.as_bytes())).join(hash)
+    }
+}
+
+/// Return the default cache directory, following the XDG convention.
+pub fn default_dir() -> PathBuf {
+    match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("percival"),
+        _ => {
+            let home = std::env::var_os("HOME").unwrap_or_default();
+            PathBuf::from(home).join(".cache").join("percival")
+        }
+    }
+}
+
+/// Encode the contents of a file or import as a data URL.
+pub fn data_url(path: impl AsRef<Path>, data: &[u8]) -> String {
+    let mime = match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+        Some("csv") => "text/csv",
+        Some("tsv") => "text/tab-separated-values",
+        Some("jsonl" | "ndjson") => "application/x-ndjson",
+        _ => "application/json",
+    };
+    format!("data:{};base64,{}", mime, base64::encode(data))
+}
+
+fn parse_lockfile(lockfile: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut locked = BTreeMap::new();
+    for (i, line) in lockfile.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(' ') {
+            Some((uri, hash)) => locked.insert(uri.into(), hash.trim().into()),
+            None => return Err(format!("invalid lockfile entry on line {}", i + 1)),
+        };
+    }
+    Ok(locked)
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Imports with an unknown scheme are never downloaded, so these tests
+    // only see the entries that they put in the cache themselves.
+    const URI: &str = "s3://bucket/cars.jsonl";
+    const DATA: &[u8] = b"{\"name\": \"beetle\"}\n";
+
+    /// Create an empty cache directory that is unique to a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "percival-cache-test-{}-{}",
+            std::process::id(),
+            name,
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Store data in the cache under a hash, returning the lockfile entry.
+    fn seed(dir: &Path, hash: &str, data: &[u8]) -> String {
+        let cache = Cache::new(dir.into(), Mode::Locked, "").unwrap();
+        let path = cache.entry(URI, hash);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+        format!("{} {}\n", URI, hash)
+    }
+
+    #[test]
+    fn lockfile_entries() {
+        let lockfile = "# comment\n\nhttps://example.com/a.json abc\n  gh://b/c.csv def  \n";
+        let locked = parse_lockfile(lockfile).unwrap();
+        assert_eq!(locked.len(), 2);
+        assert_eq!(locked["https://example.com/a.json"], "abc");
+        assert_eq!(locked["gh://b/c.csv"], "def");
+
+        assert_eq!(
+            parse_lockfile("# comment\nhttps://example.com/a.json\n"),
+            Err("invalid lockfile entry on line 2".into()),
+        );
+    }
+
+    #[test]
+    fn locked_from_cache() {
+        let dir = temp_dir("locked");
+        let lockfile = seed(&dir, &sha256(DATA), DATA);
+
+        let cache = Cache::new(dir.clone(), Mode::Locked, &lockfile).unwrap();
+        assert_eq!(cache.resolve(URI), Ok(data_url(URI, DATA)));
+        assert!(cache.lockfile().ends_with(&lockfile));
+        assert!(data_url(URI, DATA).starts_with("data:application/x-ndjson;base64,"));
+
+        // Imports that are not locked yet are downloaded.
+        let cache = Cache::new(dir.clone(), Mode::Locked, "").unwrap();
+        assert_eq!(cache.resolve(URI), Err("cannot be cached".into()));
+
+        let cache = Cache::new(dir.clone(), Mode::Frozen, &lockfile).unwrap();
+        assert_eq!(cache.resolve(URI), Ok(data_url(URI, DATA)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn update_ignores_cache() {
+        let dir = temp_dir("update");
+        let lockfile = seed(&dir, &sha256(DATA), DATA);
+
+        let cache = Cache::new(dir.clone(), Mode::Update, &lockfile).unwrap();
+        assert_eq!(cache.resolve(URI), Err("cannot be cached".into()));
+        assert!(!cache.lockfile().contains(URI));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn frozen_requires_cache() {
+        let dir = temp_dir("frozen");
+
+        let cache = Cache::new(dir.clone(), Mode::Frozen, "").unwrap();
+        assert_eq!(
+            cache.resolve(URI),
+            Err("not found in lockfile, run with --lock to add it".into()),
+        );
+
+        let hash = sha256(DATA);
+        let lockfile = format!("{} {}\n", URI, hash);
+        let cache = Cache::new(dir.clone(), Mode::Frozen, &lockfile).unwrap();
+        assert_eq!(
+            cache.resolve(URI),
+            Err(format!("sha256 {} is missing from the cache", hash)),
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let dir = temp_dir("mismatch");
+        let hash = sha256(DATA);
+        let lockfile = seed(&dir, &hash, b"[]");
+
+        for mode in [Mode::Locked, Mode::Frozen] {
+            let cache = Cache::new(dir.clone(), mode, &lockfile).unwrap();
+            assert_eq!(
+                cache.resolve(URI),
+                Err(format!(
+                    "content hash mismatch, expected sha256 {} but found {}",
+                    hash,
+                    sha256(b"[]"),
+                )),
+            );
+            assert!(!cache.lockfile().contains(URI));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,20 +1,38 @@
 //! Crate containing code for the `percival-cli` binary.
 
 use std::{
-    fs::read_to_string,
+    fs::{self, read_to_string},
     io::{self, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{self, Command, Stdio},
+    rc::Rc,
+    time::Duration,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use percival::{codegen::compile, errors::format_errors, parser::Grammar};
+use percival::{
+    codegen::{compile_with, Backend, CompileOptions},
+    errors::{format_errors, format_expansion_error},
+    lint, module,
+    parser::Grammar,
+};
+
+use crate::cache::{Cache, Mode};
+
+mod cache;
+mod node;
+mod notebook;
+mod testing;
 
 /// Convenience CLI for testing the Percival language compiler.
 #[derive(Parser, Debug)]
 #[clap(name = "Percival")]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Opt {
+    #[clap(subcommand)]
+    command: Option<Cmd>,
+
     /// Input file (default: read from stdin).
     #[clap(name = "FILE", parse(from_os_str))]
     input: Option<PathBuf>,
@@ -22,13 +40,82 @@ struct Opt {
     /// Runs prettier and bat on the output.
     #[clap(short, long)]
     format: bool,
+
+    /// Data structures used by the generated code ("immutable" or "native",
+    /// default: immutable). Notebooks always use the native backend.
+    #[clap(short, long)]
+    backend: Option<Backend>,
+
+    /// Abort evaluation after this many iterations for any one group of
+    /// mutually recursive relations.
+    #[clap(long, global = true)]
+    max_iterations: Option<u64>,
+
+    /// Abort evaluation when a relation grows past this many tuples.
+    #[clap(long, global = true)]
+    max_tuples: Option<u64>,
+
+    /// Abort evaluation after this many milliseconds.
+    #[clap(long, global = true)]
+    time_limit: Option<u64>,
+
+    /// Reject JavaScript between backticks, unless it is a native expression.
+    #[clap(long, global = true)]
+    sandbox: bool,
+
+    /// Download remote imports into the cache and write a lockfile next to
+    /// the input file. Once a lockfile exists, imports are loaded from the
+    /// cache and checked against it.
+    #[clap(long, global = true, conflicts_with = "frozen")]
+    lock: bool,
+
+    /// Only load imports from the cache, failing if they are not locked.
+    #[clap(long, global = true)]
+    frozen: bool,
+
+    /// Directory for cached imports (default: ~/.cache/percival).
+    #[clap(long, global = true, parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Check the assertions and test blocks in a program.
+    Test {
+        /// Input file (default: read from stdin).
+        #[clap(name = "FILE", parse(from_os_str))]
+        input: Option<PathBuf>,
+    },
 }
 
 /// Run the main program.
 fn main() {
-    let opt = Opt::parse();
+    let mut opt = Opt::parse();
+    if let Some(Cmd::Test { input }) = &mut opt.command {
+        opt.input = input.take();
+    }
 
-    let mut src = match opt.input {
+    // Relative `file://` imports and modules are resolved from the input
+    // file's directory.
+    let base_dir = match &opt.input {
+        Some(path) => path.parent().unwrap_or(Path::new("")).to_path_buf(),
+        None => PathBuf::new(),
+    };
+
+    let lock_path = opt.input.as_ref().map(|path| {
+        let mut path = path.clone().into_os_string();
+        path.push(".lock");
+        PathBuf::from(path)
+    });
+    let cache = match open_cache(&opt, lock_path.as_deref()) {
+        Ok(cache) => cache.map(Rc::new),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let mut src = match &opt.input {
         Some(path) => read_to_string(path).unwrap(),
         None => {
             let mut buf = String::new();
@@ -40,6 +127,67 @@ fn main() {
         src += "\n";
     }
 
+    // Modules are loaded from paths relative to the input file's directory.
+    let load_module = |path: &str| {
+        let path = base_dir.join(path);
+        read_to_string(&path).map_err(|err| format!("{}: {}", path.display(), err))
+    };
+    let mut options = CompileOptions::new()
+        .backend(opt.backend.unwrap_or_default())
+        .sandbox(opt.sandbox)
+        .resolver("file", {
+            let base_dir = base_dir.clone();
+            move |uri: &str| resolve_file(&base_dir, uri)
+        });
+    if let Some(cache) = &cache {
+        for scheme in ["http", "https", "gh", "npm"] {
+            let cache = Rc::clone(cache);
+            options = options.resolver(scheme, move |uri: &str| cache.resolve(uri));
+        }
+    }
+    if let Some(limit) = opt.max_iterations {
+        options = options.max_iterations(limit);
+    }
+    if let Some(limit) = opt.max_tuples {
+        options = options.max_tuples(limit);
+    }
+    if let Some(millis) = opt.time_limit {
+        options = options.time_limit(Duration::from_millis(millis));
+    }
+    let write_lockfile = || {
+        if let (Some(cache), Some(path)) = (&cache, &lock_path) {
+            if cache.mode() != Mode::Frozen {
+                if let Err(err) = fs::write(path, cache.lockfile()) {
+                    eprintln!("Error: {}: {}", path.display(), err);
+                    process::exit(1);
+                }
+            }
+        }
+    };
+
+    // Notebooks are evaluated as a whole, instead of compiled to one program.
+    if percival::notebook::is_notebook(&src) {
+        if opt.command.is_some() {
+            eprintln!("Error: The test command does not support notebooks");
+            process::exit(1);
+        }
+        if opt.backend.is_some() {
+            eprintln!("Error: Notebooks are evaluated with the native backend, so --backend is not supported");
+            process::exit(1);
+        }
+        match notebook::run(&src, &options, &load_module) {
+            Ok(output) => {
+                write_lockfile();
+                println!("{}", output);
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
     let grammar = Grammar::new();
     let prog = match grammar.parse(&src[..]) {
         Ok(prog) => prog,
@@ -49,8 +197,29 @@ fn main() {
         }
     };
 
-    match compile(&prog) {
+    let prog = match module::link(&prog, &load_module) {
+        Ok(prog) => prog,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    };
+
+    // Lint after linking, so that warnings also cover the rules of modules.
+    for warning in lint::check(&prog) {
+        eprintln!("Warning: {}", warning);
+    }
+
+    if let Some(Cmd::Test { .. }) = opt.command {
+        let passed = testing::run(&prog, &options);
+        write_lockfile();
+        process::exit(if passed { 0 } else { 1 });
+    }
+
+    match compile_with(&prog, &options) {
         Ok(js) => {
+            write_lockfile();
+
             if !opt.format {
                 println!("{}", js);
             } else {
@@ -79,6 +248,46 @@ fn main() {
                 child.wait().unwrap();
             }
         }
-        Err(err) => eprintln!("Error: {}", err),
+        Err(err) => {
+            match format_expansion_error(&src, &err, &load_module) {
+                Some(report) => eprintln!("{}", report),
+                None => eprintln!("Error: {}", err),
+            }
+            process::exit(1);
+        }
     }
 }
+
+/// Open the import cache, if the input file is locked or being locked.
+fn open_cache(opt: &Opt, lock_path: Option<&Path>) -> Result<Option<Cache>, String> {
+    let lock_path = match lock_path {
+        Some(path) => path,
+        None if opt.lock || opt.frozen => {
+            return Err("Locking imports requires an input file".into());
+        }
+        None => return Ok(None),
+    };
+    let mode = if opt.lock {
+        Mode::Update
+    } else if opt.frozen {
+        Mode::Frozen
+    } else if lock_path.exists() {
+        Mode::Locked
+    } else {
+        return Ok(None);
+    };
+    let lockfile = match fs::read_to_string(lock_path) {
+        Ok(lockfile) => lockfile,
+        Err(_) if mode == Mode::Update => String::new(),
+        Err(err) => return Err(format!("{}: {}", lock_path.display(), err)),
+    };
+    let dir = opt.cache_dir.clone().unwrap_or_else(cache::default_dir);
+    Cache::new(dir, mode, &lockfile).map(Some)
+}
+
+/// Resolve a `file://` import by inlining the file's contents as a data URL.
+fn resolve_file(base_dir: &Path, uri: &str) -> Result<String, String> {
+    let path = base_dir.join(uri.trim_start_matches("file://"));
+    let data = fs::read(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(cache::data_url(&path, &data))
+}
@@ -0,0 +1,270 @@
+//! Evaluation of compiled programs with Node.js.
+//!
+//! Generated code expects a runtime object with the built-in aggregates and
+//! functions, and a `load` function for imports. This provides an equivalent
+//! of the browser runtime, for the same dataset formats and options.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use percival::runtime;
+
+/// Return JavaScript that defines `runtime`, the object passed to compiled
+/// programs as their second argument.
+pub fn runtime() -> String {
+    format!(
+        "{load}
+const aggregates = {aggregates};
+const functions = {functions};
+const runtime = {{ load, aggregates, functions }};
+",
+        load = LOAD,
+        aggregates = runtime::aggregates_js(),
+        functions = runtime::functions_js(),
+    )
+}
+
+/// Run a script with Node.js, returning its standard output.
+pub fn run(script: &str) -> Result<String, String> {
+    let mut child = Command::new("node")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to run node: {}", err))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .map_err(|err| format!("failed to run node: {}", err))?;
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("failed to run node: {}", err))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr.trim().into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// JavaScript that loads an import, checking its hash if one is given. This
+/// follows `load` in the browser runtime, with its own parser for CSV and TSV.
+const LOAD: &str = r#"
+const crypto = require("crypto");
+
+async function load(url, options = {}) {
+  const resp = await fetch(url);
+  if (!resp.ok) {
+    throw new Error(`Failed to fetch ${url}:\n${await resp.text()}`);
+  }
+  const data = Buffer.from(await resp.arrayBuffer());
+  if (options.sha256) {
+    const actual = crypto.createHash("sha256").update(data).digest("hex");
+    if (actual !== options.sha256) {
+      throw new Error(`Integrity check failed for ${url}: expected SHA-256 ${options.sha256}, but the data has hash ${actual}`);
+    }
+  }
+  const text = data.toString("utf8");
+  const format = options.format ?? inferFormat(url, resp.headers.get("Content-Type"));
+  switch (format) {
+    case "json":
+      return JSON.parse(text);
+    case "jsonl":
+      return text.split("\n").filter((line) => line.trim()).map((line) => JSON.parse(line));
+    case "columnar":
+      return fromColumns(JSON.parse(text));
+    case "csv":
+    case "tsv":
+      return parseDsv(text, format === "csv" ? "," : "\t", options);
+    default:
+      throw new Error(`Unknown file format ${format} for ${url}`);
+  }
+}
+
+function inferFormat(url, contentType) {
+  const path = url.split(/[?#]/)[0];
+  if (path.endsWith(".json") || contentType?.match(/application\/json/i)) {
+    return "json";
+  } else if (path.match(/\.(jsonl|ndjson)$/) || contentType?.match(/application\/(x-)?(ndjson|jsonl)/i)) {
+    return "jsonl";
+  } else if (path.endsWith(".csv") || contentType?.match(/text\/csv/i)) {
+    return "csv";
+  } else if (path.endsWith(".tsv") || contentType?.match(/text\/tab-separated-values/i)) {
+    return "tsv";
+  }
+  throw new Error(`Unknown file format for ${url}. Only JSON, JSONL, CSV, and TSV are supported.
+Try adding a file extension to the URL, providing a MIME Content-Type header, or
+specifying the format in the import, like \`import data from "..." as csv\`.`);
+}
+
+function fromColumns(data) {
+  const columns = Object.keys(data);
+  const length = columns.length ? data[columns[0]].length : 0;
+  for (const column of columns) {
+    if (!Array.isArray(data[column]) || data[column].length !== length) {
+      throw new Error(`Column "${column}" does not have ${length} values`);
+    }
+  }
+  return Array.from({ length }, (_, i) =>
+    Object.fromEntries(columns.map((column) => [column, data[column][i]])));
+}
+
+function parseRows(text, delimiter) {
+  const rows = [];
+  let row = [];
+  let field = "";
+  let quoted = false;
+  for (let i = 0; i < text.length; i++) {
+    const c = text[i];
+    if (quoted) {
+      if (c !== '"') {
+        field += c;
+      } else if (text[i + 1] === '"') {
+        field += c;
+        i++;
+      } else {
+        quoted = false;
+      }
+    } else if (c === '"' && field === "") {
+      quoted = true;
+    } else if (c === delimiter) {
+      row.push(field);
+      field = "";
+    } else if (c === "\n" || c === "\r") {
+      if (c === "\r" && text[i + 1] === "\n") i++;
+      row.push(field);
+      rows.push(row);
+      row = [];
+      field = "";
+    } else {
+      field += c;
+    }
+  }
+  if (field || row.length) {
+    row.push(field);
+    rows.push(row);
+  }
+  return rows;
+}
+
+function parseDsv(text, delimiter, options) {
+  let rows = parseRows(text, options.delimiter ?? delimiter);
+  if (options.header === false) {
+    rows = rows.map((row) => Object.fromEntries(row.map((value, i) => [`column${i + 1}`, value])));
+  } else {
+    const [columns = [], ...body] = rows;
+    rows = body.map((row) => Object.fromEntries(columns.map((column, i) => [column, row[i] ?? ""])));
+  }
+  const types = Object.entries(options.types ?? {});
+  return rows.map((row) => {
+    const result = autoType({ ...row });
+    for (const [column, type] of types) {
+      result[column] = convertValue(row[column], type);
+    }
+    return result;
+  });
+}
+
+function autoType(object) {
+  for (const key in object) {
+    const value = object[key].trim();
+    let number;
+    if (!value) object[key] = null;
+    else if (value === "true") object[key] = true;
+    else if (value === "false") object[key] = false;
+    else if (value === "NaN") object[key] = NaN;
+    else if (!isNaN((number = +value))) object[key] = number;
+    else if (value.match(/^([-+]\d{2})?\d{4}(-\d{2}(-\d{2})?)?(T\d{2}:\d{2}(:\d{2}(\.\d{3})?)?(Z|[-+]\d{2}:\d{2})?)?$/)) {
+      object[key] = new Date(value);
+    }
+  }
+  return object;
+}
+
+function convertValue(value, type) {
+  if (value === undefined || value === "") {
+    return null;
+  }
+  switch (type) {
+    case "string":
+      return value;
+    case "number":
+      return Number(value);
+    case "boolean":
+      return value.toLowerCase() === "true";
+    case "date": {
+      const time = Date.parse(value);
+      return Number.isNaN(time) ? null : time;
+    }
+    default:
+      return autoType({ value }).value;
+  }
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load a data URL with the Node.js runtime, returning the rows as JSON.
+    fn load(url: &str, options: &str) -> Result<String, String> {
+        let script = format!(
+            "{}load({:?}, {}).then((rows) => console.log(JSON.stringify(rows)));",
+            runtime(),
+            url,
+            options,
+        );
+        run(&script).map(|output| output.trim().into())
+    }
+
+    #[test]
+    fn load_formats() {
+        assert_eq!(
+            load(
+                "data:text/csv,a,b%0A1,%22x,%20%22%22y%22%22%22%0A,true",
+                "{}"
+            )
+            .unwrap(),
+            r#"[{"a":1,"b":"x, \"y\""},{"a":null,"b":true}]"#,
+        );
+        assert_eq!(
+            load("data:text/tab-separated-values,1%092", "{ header: false }").unwrap(),
+            r#"[{"column1":1,"column2":2}]"#,
+        );
+        assert_eq!(
+            load(
+                "data:text/plain,a;b%0A01;x",
+                r#"{ format: "csv", delimiter: ";", types: { a: "string" } }"#,
+            )
+            .unwrap(),
+            r#"[{"a":"01","b":"x"}]"#,
+        );
+        assert_eq!(
+            load("data:application/x-ndjson,%7B%22a%22:1%7D%0A", "{}").unwrap(),
+            r#"[{"a":1}]"#,
+        );
+        let err = load("data:text/plain,a", "{}").unwrap_err();
+        assert!(err.contains("Unknown file format"), "{}", err);
+    }
+
+    #[test]
+    fn functions_propagate_null() {
+        // Call each function with a null in every position, and "1" elsewhere.
+        let script = format!(
+            r#"{}
+for (const [name, f] of Object.entries(functions)) {{
+  for (let i = 0; i < f.length; i++) {{
+    const args = Array(f.length).fill("1");
+    args[i] = null;
+    if (f(...args) !== null) console.log(`${{name}} ${{i}}`);
+  }}
+}}"#,
+            runtime(),
+        );
+        assert_eq!(run(&script).unwrap(), "");
+    }
+}
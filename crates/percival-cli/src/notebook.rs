@@ -0,0 +1,89 @@
+//! Evaluation of whole notebooks, with cells connected by their relations.
+
+use percival::{
+    codegen::{compile_with, Backend, CompileOptions},
+    errors::{format_errors, format_expansion_error},
+    lint,
+    module::{self, ModuleLoader},
+    notebook::{self, CellDeps, CellType},
+    parser::Grammar,
+};
+
+use crate::node;
+
+/// Compile every code cell of a notebook and evaluate them in topological
+/// order, returning the relations produced by all cells as JSON. Plot cells
+/// are checked for their dependencies, but not rendered.
+pub fn run(
+    src: &str,
+    options: &CompileOptions,
+    loader: &dyn ModuleLoader,
+) -> Result<String, String> {
+    let options = options.clone().backend(Backend::Native);
+    let grammar = Grammar::new();
+    let cells = notebook::unmarshal(src);
+
+    let mut deps = Vec::new();
+    let mut compiled = Vec::new();
+    for (i, cell) in cells.iter().enumerate() {
+        let (cell_deps, js) = match cell.cell_type {
+            CellType::Markdown => (None, None),
+            CellType::Plot => match CellDeps::plot(&cell.value) {
+                Some(cell_deps) => (Some(cell_deps), None),
+                None => return Err(notebook::Error::InvalidPlot(i).to_string()),
+            },
+            CellType::Code => {
+                let mut src = cell.value.clone();
+                if !src.ends_with('\n') {
+                    src += "\n";
+                }
+                let prog = grammar.parse(&src).map_err(|errors| {
+                    format!("Cell {}:\n{}", i + 1, format_errors(&src, errors))
+                })?;
+                let prog = module::link(&prog, loader)
+                    .map_err(|err| format!("Cell {}: {}", i + 1, err))?;
+                for warning in lint::check(&prog) {
+                    eprintln!("Warning: Cell {}: {}", i + 1, warning);
+                }
+                let js =
+                    compile_with(&prog, &options).map_err(|err| {
+                        match format_expansion_error(&src, &err, loader) {
+                            Some(report) => format!("Cell {}:\n{}", i + 1, report),
+                            None => format!("Cell {}: {}", i + 1, err),
+                        }
+                    })?;
+                (Some(CellDeps::program(&prog)), Some(js))
+            }
+        };
+        deps.push(cell_deps);
+        compiled.push(js);
+    }
+
+    let order = notebook::evaluation_order(&deps).map_err(|err| err.to_string())?;
+
+    let mut script = node::runtime();
+    script += "const outputs = {};\n";
+    for i in order {
+        if let Some(js) = &compiled[i] {
+            script += &format!(
+                "async function cell_{i}(__percival_deps, __percival) {{
+{js}
+}}
+try {{
+    Object.assign(outputs, await cell_{i}(outputs, runtime));
+}} catch (error) {{
+    console.error(`Cell {n}: ${{error instanceof Error ? error.message : error}}`);
+    process.exit(1);
+}}
+",
+                i = i,
+                n = i + 1,
+                js = js,
+            );
+        }
+    }
+    script += "console.log(JSON.stringify(outputs));\n";
+    // Top-level await is only available in modules.
+    let script = format!("(async () => {{\n{}}})();\n", script);
+    node::run(&script).map(|output| output.trim_end().into())
+}
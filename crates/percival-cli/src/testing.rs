@@ -0,0 +1,399 @@
+//! Test runner for assertions and `test` blocks in Percival programs.
+//!
+//! Each assertion is lowered into a hidden rule that binds both of its sides
+//! and compares them, along with a rule for each aggregate in the assertion
+//! that collects the tuples of its subquery. These are reported as
+//! counterexamples when the assertion fails. The lowered program is compiled
+//! with the native backend and evaluated by Node.js.
+
+use std::collections::BTreeMap;
+
+use percival::{
+    ast::{Assert, BinaryOp, Clause, Fact, Origin, Program, Rule, Value},
+    codegen::{compile_with, Backend, CompileOptions},
+};
+
+use crate::node;
+
+/// Largest number of counterexamples shown for each failing assertion.
+const MAX_COUNTEREXAMPLES: usize = 10;
+
+/// Outcome of evaluating a single assertion, with values as JSON.
+#[derive(Debug, Default, PartialEq)]
+struct Outcome {
+    passed: bool,
+    left: String,
+    right: String,
+    counterexamples: Vec<String>,
+}
+
+/// Hidden relations that an assertion is lowered into.
+struct Check {
+    /// Relation with the values of both sides and whether they are equal.
+    name: String,
+    /// Relations with the tuples matched by each aggregate's subquery.
+    counterexamples: Vec<String>,
+}
+
+/// Run all of the assertions in a program and in its test blocks, printing
+/// the results. Returns whether every assertion passed.
+pub fn run(prog: &Program, options: &CompileOptions) -> bool {
+    let options = options.clone().backend(Backend::Native);
+    let mut suites = vec![(None, &[][..], &prog.asserts[..])];
+    for test in &prog.tests {
+        suites.push((Some(&test.name), &test.rules[..], &test.asserts[..]));
+    }
+
+    let (mut passed, mut failed) = (0, 0);
+    for (name, rules, asserts) in suites {
+        if asserts.is_empty() {
+            continue;
+        }
+        let label = |i: usize| match name {
+            Some(name) => format!("test {:?}, assertion {}", name, i + 1),
+            None => format!("assertion {}", i + 1),
+        };
+
+        let (lowered, checks) = lower(prog, rules, asserts);
+        let outcomes = compile_with(&lowered, &options)
+            .map_err(|err| err.to_string())
+            .and_then(|js| evaluate(&lowered, &js, &checks));
+        let outcomes = match outcomes {
+            Ok(outcomes) => outcomes,
+            Err(err) => {
+                failed += asserts.len();
+                match name {
+                    Some(name) => println!("FAILED  test {:?}", name),
+                    None => println!("FAILED  assertions"),
+                }
+                println!("    error: {}", err.replace('\n', "\n    "));
+                continue;
+            }
+        };
+
+        for (i, outcome) in outcomes.into_iter().enumerate() {
+            if outcome.passed {
+                passed += 1;
+                println!("ok      {}", label(i));
+                continue;
+            }
+            failed += 1;
+            println!("FAILED  {}", label(i));
+            println!("    left:  {}", outcome.left);
+            println!("    right: {}", outcome.right);
+            let counterexamples = outcome.counterexamples;
+            if !counterexamples.is_empty() {
+                println!("    counterexamples:");
+                for row in counterexamples.iter().take(MAX_COUNTEREXAMPLES) {
+                    println!("        {}", row);
+                }
+                if counterexamples.len() > MAX_COUNTEREXAMPLES {
+                    let more = counterexamples.len() - MAX_COUNTEREXAMPLES;
+                    println!("        ... and {} more", more);
+                }
+            }
+        }
+    }
+
+    let result = if failed == 0 { "ok" } else { "FAILED" };
+    println!();
+    println!(
+        "test result: {}. {} passed; {} failed",
+        result, passed, failed
+    );
+    failed == 0
+}
+
+/// Lower assertions into hidden rules, added to a copy of the program along
+/// with the extra rules of a test block.
+fn lower(prog: &Program, rules: &[Rule], asserts: &[Assert]) -> (Program, Vec<Check>) {
+    let mut lowered = Program {
+        asserts: Vec::new(),
+        tests: Vec::new(),
+        ..prog.clone()
+    };
+    lowered.rules.extend(rules.iter().cloned());
+    // Every relation is visible to the test runner.
+    lowered.exports.clear();
+    lowered.private.clear();
+
+    let mut checks = Vec::new();
+    for (i, assert) in asserts.iter().enumerate() {
+        let name = format!("__assert_{}", i);
+        let id = |name: &str| Value::Id(name.into());
+        lowered.rules.push(Rule {
+            goal: Fact {
+                name: name.clone(),
+                props: BTreeMap::from([
+                    (
+                        "passed".into(),
+                        Value::Binary(
+                            BinaryOp::Eq,
+                            Box::new(id("__left")),
+                            Box::new(id("__right")),
+                        ),
+                    ),
+                    ("left".into(), id("__left")),
+                    ("right".into(), id("__right")),
+                ]),
+            },
+            clauses: vec![
+                Clause::Binding("__left".into(), assert.left.clone()),
+                Clause::Binding("__right".into(), assert.right.clone()),
+            ],
+            origin: Origin::default(),
+        });
+
+        let mut counterexamples = Vec::new();
+        let mut subqueries = Vec::new();
+        collect_subqueries(&assert.left, &mut subqueries);
+        collect_subqueries(&assert.right, &mut subqueries);
+        for subquery in subqueries {
+            let vars = bound_vars(subquery);
+            if vars.is_empty() {
+                continue;
+            }
+            let counterexample = format!("{}_{}", name, counterexamples.len());
+            lowered.rules.push(Rule {
+                goal: Fact {
+                    name: counterexample.clone(),
+                    props: vars
+                        .into_iter()
+                        .map(|var| (var.clone(), Value::Id(var)))
+                        .collect(),
+                },
+                clauses: subquery.to_vec(),
+                origin: Origin::default(),
+            });
+            counterexamples.push(counterexample);
+        }
+        checks.push(Check {
+            name,
+            counterexamples,
+        });
+    }
+    (lowered, checks)
+}
+
+/// Collect the subqueries of aggregates in a value, outside of other subqueries.
+fn collect_subqueries<'a>(value: &'a Value, subqueries: &mut Vec<&'a [Clause]>) {
+    match value {
+        Value::Aggregate(aggregate) => subqueries.push(&aggregate.subquery),
+        Value::List(items) | Value::Call(_, items) => {
+            for item in items {
+                collect_subqueries(item, subqueries);
+            }
+        }
+        Value::Record(fields) => {
+            for field in fields.values() {
+                collect_subqueries(field, subqueries);
+            }
+        }
+        Value::Binary(_, lhs, rhs) => {
+            collect_subqueries(lhs, subqueries);
+            collect_subqueries(rhs, subqueries);
+        }
+        Value::Unary(_, value) => collect_subqueries(value, subqueries),
+        Value::Lattice(lattice) => collect_subqueries(&lattice.value, subqueries),
+        Value::Id(_) | Value::Literal(_) | Value::Expr(_) => (),
+    }
+}
+
+/// Return the variables bound by the facts and bindings of a subquery.
+fn bound_vars(clauses: &[Clause]) -> Vec<String> {
+    let mut vars: Vec<String> = Vec::new();
+    for clause in clauses {
+        let bound = match clause {
+            Clause::Fact(fact) => fact
+                .props
+                .values()
+                .flat_map(|value| value.pattern_vars())
+                .collect(),
+            Clause::Binding(name, _) => vec![&name[..]],
+            _ => Vec::new(),
+        };
+        for var in bound {
+            if !vars.iter().any(|seen| seen == var) {
+                vars.push(var.into());
+            }
+        }
+    }
+    vars
+}
+
+/// Evaluate a compiled program with Node.js, returning the outcome of each check.
+fn evaluate(prog: &Program, js: &str, checks: &[Check]) -> Result<Vec<Outcome>, String> {
+    let deps: Vec<_> = prog
+        .deps()
+        .iter()
+        .map(|dep| format!("{:?}: []", dep))
+        .collect();
+    let checks_js: Vec<_> = checks
+        .iter()
+        .map(|check| format!("[{:?}, {:?}]", check.name, check.counterexamples))
+        .collect();
+    let script = format!(
+        "{runtime}{report}
+const deps = {{{deps}}};
+const checks = [{checks}];
+async function evaluate(__percival_deps, __percival) {{
+{js}
+}}
+report(evaluate(deps, runtime), checks);
+",
+        runtime = node::runtime(),
+        report = REPORT,
+        deps = deps.join(", "),
+        checks = checks_js.join(", "),
+        js = js,
+    );
+    let output = node::run(&script)?;
+    Ok(parse_report(&output, checks.len()))
+}
+
+/// Parse the lines printed by [`REPORT`] into the outcome of each check.
+fn parse_report(output: &str, checks: usize) -> Vec<Outcome> {
+    // Checks without any output are treated as failures.
+    let mut outcomes: Vec<_> = (0..checks).map(|_| Outcome::default()).collect();
+    for line in output.lines() {
+        let mut parts = line.splitn(3, ' ');
+        let (index, kind, json) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(index), Some(kind), json) => (index, kind, json.unwrap_or_default()),
+            _ => continue,
+        };
+        let outcome = match index.parse().ok().and_then(|i: usize| outcomes.get_mut(i)) {
+            Some(outcome) => outcome,
+            None => continue,
+        };
+        match kind {
+            "pass" => outcome.passed = true,
+            "left" => outcome.left = json.into(),
+            "right" => outcome.right = json.into(),
+            "row" => outcome.counterexamples.push(json.into()),
+            _ => (),
+        }
+    }
+    outcomes
+}
+
+/// JavaScript that reports the result of each check, one line at a time as
+/// `<index> <kind> <json>`.
+const REPORT: &str = r#"
+function report(results, checks) {
+  results.then((results) => {
+    for (const [i, [check, counterexamples]] of checks.entries()) {
+      const [{ passed, left, right }] = results[check];
+      if (passed) {
+        console.log(`${i} pass`);
+        continue;
+      }
+      console.log(`${i} left ${JSON.stringify(left)}`);
+      console.log(`${i} right ${JSON.stringify(right)}`);
+      for (const name of counterexamples) {
+        for (const row of results[name]) {
+          console.log(`${i} row ${JSON.stringify(row)}`);
+        }
+      }
+    }
+  }, (error) => {
+    console.error(error instanceof Error ? error.message : error);
+    process.exit(1);
+  });
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use percival::parser::Grammar;
+
+    use super::*;
+
+    fn parse(src: &str) -> Program {
+        Grammar::new().parse(src).unwrap()
+    }
+
+    #[test]
+    fn subquery_bound_vars() {
+        let prog = parse("assert count[1] { edge(x, y: z), w = z + 1, z > 0, edge(x) } = 0.");
+        let mut subqueries = Vec::new();
+        collect_subqueries(&prog.asserts[0].left, &mut subqueries);
+        assert_eq!(subqueries.len(), 1);
+        assert_eq!(bound_vars(subqueries[0]), vec!["x", "z", "w"]);
+        assert!(bound_vars(&[]).is_empty());
+    }
+
+    #[test]
+    fn lower_asserts() {
+        let prog = parse(
+            "edge(x: 1, y: 2).
+edge(x: 2, y: 2).
+assert count[1] { edge(x, y: 2) } = 2.
+assert count[1] { edge(x, y: 3) } = count[1] { edge(x: 1, y) }.
+assert [1, 2] = [1, 2].",
+        );
+        let (lowered, checks) = lower(&prog, &[], &prog.asserts);
+        assert!(lowered.asserts.is_empty());
+        assert_eq!(
+            checks
+                .iter()
+                .map(|check| &check.name[..])
+                .collect::<Vec<_>>(),
+            vec!["__assert_0", "__assert_1", "__assert_2"],
+        );
+        assert_eq!(checks[0].counterexamples, vec!["__assert_0_0"]);
+        assert_eq!(
+            checks[1].counterexamples,
+            vec!["__assert_1_0", "__assert_1_1"],
+        );
+        assert!(checks[2].counterexamples.is_empty());
+        assert_eq!(lowered.rules.len(), prog.rules.len() + 6);
+
+        let options = CompileOptions::new().backend(Backend::Native);
+        let js = compile_with(&lowered, &options).unwrap();
+        let outcomes = evaluate(&lowered, &js, &checks).unwrap();
+        assert!(outcomes[0].passed);
+        assert!(outcomes[2].passed);
+
+        // The failing assertion reports both sides and the rows it counted.
+        assert!(!outcomes[1].passed);
+        assert_eq!(outcomes[1].left, "0");
+        assert_eq!(outcomes[1].right, "1");
+        assert_eq!(outcomes[1].counterexamples, vec![r#"{"y":2}"#]);
+    }
+
+    #[test]
+    fn report_lines() {
+        let output = "0 pass
+1 left {\"a\": 1}
+1 right [1, 2]
+1 row {\"x\": 1}
+1 row {\"x\": 2}
+2 pass
+7 pass
+garbage
+";
+        let outcomes = parse_report(output, 3);
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome {
+                    passed: true,
+                    ..Outcome::default()
+                },
+                Outcome {
+                    passed: false,
+                    left: r#"{"a": 1}"#.into(),
+                    right: "[1, 2]".into(),
+                    counterexamples: vec![r#"{"x": 1}"#.into(), r#"{"x": 2}"#.into()],
+                },
+                Outcome {
+                    passed: true,
+                    ..Outcome::default()
+                },
+            ],
+        );
+
+        // Checks that print nothing, such as after a crash, have failed.
+        assert_eq!(parse_report("", 1), vec![Outcome::default()]);
+    }
+}
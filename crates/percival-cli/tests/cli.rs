@@ -0,0 +1,46 @@
+use std::{fs, path::PathBuf, process::Command};
+
+/// Write files into a directory that is unique to a test.
+fn temp_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("percival-cli-test-{}-{}", std::process::id(), name,));
+    let _ = fs::remove_dir_all(&dir);
+    for (path, src) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, src).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn test_command_with_modules_and_templates() {
+    let dir = temp_dir(
+        "modules",
+        &[
+            ("lib/graph.pcv", "export path(x, y) :- edge(x, y).\n"),
+            (
+                "main.pcv",
+                r#"use "lib/graph.pcv"
+edge(x: 1, y: 2).
+edge(x: 2, y: 3).
+template close(E, R) {
+    step(x, y) :- E(x, y).
+    R(x, y) :- step(x, y).
+    R(x, y) :- R(x, y: z), step(x: z, y).
+}
+close!(graph.path, reach).
+assert count[1] { reach(x, y) } = 3.
+"#,
+            ),
+        ],
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_percival-cli"))
+        .arg("test")
+        .arg(dir.join("main.pcv"))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("ok      assertion 1"), "{}", stdout);
+}
@@ -2,10 +2,20 @@
 
 #![warn(missing_docs)]
 
+use std::{rc::Rc, time::Duration};
+
 use wasm_bindgen::prelude::*;
 use yansi::Paint;
 
-use percival::{ast::Program, codegen, errors::format_errors, parser::Grammar};
+use percival::{
+    ast::Program,
+    codegen,
+    errors::{format_errors, format_expansion_error},
+    lint,
+    module::{self, ModuleLoader},
+    parser::Grammar,
+    runtime,
+};
 
 /// Set a panic listener to display better error messages.
 #[wasm_bindgen(start)]
@@ -23,6 +33,12 @@ pub fn start() {
 /// Compile a Percival program and return the result.
 #[wasm_bindgen]
 pub fn compile(src: &str) -> CompilerResult {
+    compile_with(src, &CompileOptions::new())
+}
+
+/// Compile a Percival program with custom options and return the result.
+#[wasm_bindgen]
+pub fn compile_with(src: &str, options: &CompileOptions) -> CompilerResult {
     thread_local! {
         static GRAMMAR: Grammar = Grammar::new();
     }
@@ -36,13 +52,116 @@ pub fn compile(src: &str) -> CompilerResult {
             .parse(&src[..])
             .map_err(|err| format_errors(&src[..], err))
             .and_then(|prog| {
-                let js = codegen::compile(&prog)
+                let loader = options.loader.as_deref().unwrap_or(&no_modules);
+                let prog = module::link(&prog, loader)
                     .map_err(|err| format!("{} {}", Paint::red("Error:"), err))?;
+                let js = codegen::compile_with(&prog, &options.options).map_err(|err| {
+                    format_expansion_error(&src, &err, loader)
+                        .unwrap_or_else(|| format!("{} {}", Paint::red("Error:"), err))
+                })?;
                 Ok((prog, js))
             })
     }))
 }
 
+/// JavaScript source for the built-in aggregates that compiled code expects.
+#[wasm_bindgen]
+pub fn runtime_aggregates() -> String {
+    runtime::aggregates_js()
+}
+
+/// JavaScript source for the built-in functions that compiled code expects.
+#[wasm_bindgen]
+pub fn runtime_functions() -> String {
+    runtime::functions_js()
+}
+
+/// Options passed to the compiler, configured through setter methods.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct CompileOptions {
+    options: codegen::CompileOptions,
+    loader: Option<Rc<dyn ModuleLoader>>,
+}
+
+#[wasm_bindgen]
+impl CompileOptions {
+    /// Construct a new set of options with default values.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the backend by name, either `"immutable"` or `"native"`.
+    pub fn set_backend(&mut self, name: &str) -> Result<(), JsValue> {
+        let backend = name
+            .parse()
+            .map_err(|err: String| JsValue::from_str(&err))?;
+        self.options = self.options.clone().backend(backend);
+        Ok(())
+    }
+
+    /// Abort evaluation after this many iterations of the loop computing any
+    /// one group of mutually recursive relations.
+    pub fn set_max_iterations(&mut self, limit: u32) {
+        self.options = self.options.clone().max_iterations(limit.into());
+    }
+
+    /// Abort evaluation when any relation grows past this many tuples.
+    pub fn set_max_tuples(&mut self, limit: u32) {
+        self.options = self.options.clone().max_tuples(limit.into());
+    }
+
+    /// Abort evaluation when the main loop runs for longer than this many
+    /// milliseconds.
+    pub fn set_time_limit(&mut self, millis: u32) {
+        self.options = self
+            .options
+            .clone()
+            .time_limit(Duration::from_millis(millis.into()));
+    }
+
+    /// Reject raw JavaScript in the program, for evaluating untrusted code.
+    pub fn set_sandbox(&mut self, sandbox: bool) {
+        self.options = self.options.clone().sandbox(sandbox);
+    }
+
+    /// Resolve import URIs with a scheme, such as `"file"`, by calling a host
+    /// function that takes the URI and returns a URL for the loader.
+    pub fn set_resolver(&mut self, scheme: &str, resolve: js_sys::Function) {
+        self.options = self.options.clone().resolver(scheme, move |uri: &str| {
+            call_host(&resolve, uri, "resolver")
+        });
+    }
+
+    /// Load the source of modules included with `use` by calling a host
+    /// function that takes the module's path and returns its source code.
+    pub fn set_module_loader(&mut self, load: js_sys::Function) {
+        self.loader = Some(Rc::new(move |path: &str| {
+            call_host(&load, path, "module loader")
+        }));
+    }
+}
+
+/// Module loader used when the host does not provide one.
+fn no_modules(_: &str) -> Result<String, String> {
+    Err("no module loader was provided".into())
+}
+
+/// Call a host function with a string, expecting a string in return.
+fn call_host(func: &js_sys::Function, arg: &str, name: &str) -> Result<String, String> {
+    let value = func
+        .call1(&JsValue::NULL, &JsValue::from_str(arg))
+        .map_err(|err| {
+            err.as_string()
+                .or_else(|| js_sys::Error::from(err).message().as_string())
+                .unwrap_or_else(|| format!("{} threw an exception", name))
+        })?;
+    value
+        .as_string()
+        .ok_or_else(|| format!("{} did not return a string", name))
+}
+
 /// The result of a compilation.
 #[wasm_bindgen]
 pub struct CompilerResult(Result<(Program, String), String>);
@@ -64,17 +183,26 @@ impl CompilerResult {
         })
     }
 
-    /// Returns the names of relations produced by this program, including imports.
+    /// Returns the names of relations visible to other cells, including imports.
     pub fn results(&self) -> Option<Vec<JsValue>> {
         self.0.as_ref().ok().map(|(prog, _)| {
             prog.results()
                 .into_iter()
-                .chain(prog.imports().into_iter())
                 .map(|s| JsValue::from_str(&s))
                 .collect()
         })
     }
 
+    /// Returns messages for any warnings from static analysis of the program.
+    pub fn warnings(&self) -> Option<Vec<JsValue>> {
+        self.0.as_ref().ok().map(|(prog, _)| {
+            lint::check(prog)
+                .into_iter()
+                .map(|warning| JsValue::from_str(&warning.to_string()))
+                .collect()
+        })
+    }
+
     /// Returns a string representation of any errors during compilation.
     pub fn err(&self) -> Option<String> {
         self.0.as_ref().err().cloned()
@@ -4,7 +4,7 @@
 
 use std::iter::IntoIterator;
 
-use percival_wasm::compile;
+use percival_wasm::{compile, compile_with, CompileOptions};
 
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_test::*;
@@ -17,6 +17,15 @@ fn basic_compile() {
     assert!(compile("tc(x,").err().is_some());
 }
 
+#[wasm_bindgen_test]
+fn compile_options() {
+    let mut options = CompileOptions::new();
+    assert!(options.set_backend("unknown").is_err());
+    assert!(options.set_backend("native").is_ok());
+    let js = compile_with("tc(x: 3, y: 4).", &options).js().unwrap();
+    assert!(!js.contains("Immutable"));
+}
+
 #[wasm_bindgen_test]
 fn deps_and_results() {
     fn to_js_vec<'a>(arr: impl IntoIterator<Item = &'a str>) -> Vec<JsValue> {
@@ -38,3 +47,14 @@ fn deps_and_results() {
     assert_eq!(result.deps(), None);
     assert_eq!(result.results(), None);
 }
+
+#[wasm_bindgen_test]
+fn lint_warnings() {
+    let result = compile("n(x: 0). n(x: `x + 1`) :- n(x).");
+    assert!(result.is_ok());
+    assert_eq!(result.warnings().map(|w| w.len()), Some(1));
+    assert_eq!(
+        compile("tc(x: 3, y: 4).").warnings().map(|w| w.len()),
+        Some(0)
+    );
+}
@@ -1,14 +1,31 @@
 //! Abstract syntax tree definitions for the Percival language.
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::Range,
+};
 
 /// A program translation unit in the Percival language.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Program {
     /// Rules that make up the program.
     pub rules: Vec<Rule>,
     /// Imports prefixed with the `import` keyword.
     pub imports: Vec<Import>,
+    /// Custom aggregates prefixed with the `aggregate` keyword.
+    pub aggregates: Vec<CustomAggregate>,
+    /// Modules of rules included with the `use` keyword.
+    pub uses: Vec<Use>,
+    /// Relations marked with the `export` keyword. If there are any, then
+    /// only these relations are visible to other cells.
+    pub exports: BTreeSet<String>,
+    /// Relations marked with the `private` keyword, hidden from other cells.
+    pub private: BTreeSet<String>,
+    /// Assertions about the results of the program, checked by `percival test`.
+    pub asserts: Vec<Assert>,
+    /// Named test blocks, each checked against the program separately.
+    pub tests: Vec<Test>,
 }
 
 /// Represents a single Horn clause.
@@ -18,6 +35,29 @@ pub struct Rule {
     pub goal: Fact,
     /// Tail or conditional assumptions of the Horn clause.
     pub clauses: Vec<Clause>,
+    /// Where the rule was written, for locating errors in it.
+    pub origin: Origin,
+}
+
+/// Source location of a rule outside of the main program's own rules.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Origin {
+    /// Path of the module that the rule was linked from.
+    pub module: Option<String>,
+    /// Template instance that the rule was expanded from.
+    pub expansion: Option<Expansion>,
+}
+
+/// An instantiation of a template, which the rules of the template were
+/// expanded from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expansion {
+    /// Name of the template.
+    pub template: String,
+    /// Span of the template's declaration in the source.
+    pub declaration: Range<usize>,
+    /// Span of the instance in the source.
+    pub instance: Range<usize>,
 }
 
 /// An element of the right-hand side of a rule.
@@ -27,8 +67,15 @@ pub enum Clause {
     Fact(Fact),
     /// Raw JavaScript conditional expression between backticks.
     Expr(String),
+    /// Boolean condition written as a native expression, such as `x < y`.
+    Condition(Value),
     /// Local variable binding within a rule.
     Binding(String, Value),
+    /// Several aggregates over one subquery, binding a variable for each.
+    MultiAggregate(MultiAggregate),
+    /// Alternative lists of clauses separated by semicolons, any of which may
+    /// hold, such as `(edge(x, y); edge(x: y, y: x))`.
+    Disjunction(Vec<Vec<Clause>>),
 }
 
 /// Literal part of a Horn clause, written in terms of relations.
@@ -49,8 +96,63 @@ pub enum Value {
     Literal(Literal),
     /// A raw JavaScript expression between backticks.
     Expr(String),
+    /// A list of values, written as `[a, b, c]`.
+    List(Vec<Value>),
+    /// A record of named values, written as `{name: a, b}`.
+    Record(BTreeMap<String, Value>),
     /// A custom aggregate operation over a subquery.
     Aggregate(Aggregate),
+    /// A lattice operation in the goal of a rule, such as `min[d]`, which only
+    /// keeps the best value for each combination of the other fields.
+    Lattice(Lattice),
+    /// A binary operation on two values, such as `x + 1` or `a < b`.
+    Binary(BinaryOp, Box<Value>, Box<Value>),
+    /// A unary operation on a value, such as `-x` or `!done`.
+    Unary(UnaryOp, Box<Value>),
+    /// A call to a built-in function, such as `abs(x)`.
+    Call(String, Vec<Value>),
+}
+
+/// Binary operators in native expressions, from highest to lowest precedence.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    /// Multiplication, written `*`.
+    Mul,
+    /// Division, written `/`.
+    Div,
+    /// Remainder, written `%`.
+    Rem,
+    /// Addition or string concatenation, written `+`.
+    Add,
+    /// Subtraction, written `-`.
+    Sub,
+    /// Equality of values, written `==`.
+    Eq,
+    /// Inequality of values, written `!=`.
+    Ne,
+    /// Less than, written `<`.
+    Lt,
+    /// Less than or equal to, written `<=`.
+    Le,
+    /// Greater than, written `>`.
+    Gt,
+    /// Greater than or equal to, written `>=`.
+    Ge,
+    /// Logical and, written `&&`.
+    And,
+    /// Logical or, written `||`.
+    Or,
+}
+
+/// Unary operators in native expressions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    /// Numeric negation, written `-`.
+    Neg,
+    /// Numeric identity, written `+`.
+    Plus,
+    /// Logical negation, written `!`.
+    Not,
 }
 
 /// Literal values supported by the Percival grammar.
@@ -58,10 +160,12 @@ pub enum Value {
 pub enum Literal {
     /// A standard floating-point number literal.
     Number(String),
-    /// A string literal, with escape sequences unevaluated.
+    /// A string literal, with escape sequences decoded.
     String(String),
     /// A boolean literal in simplest form.
     Boolean(bool),
+    /// The null literal, which also matches fields that are missing.
+    Null,
 }
 
 /// An aggregate operation over stratified dependency relations.
@@ -71,33 +175,185 @@ pub struct Aggregate {
     pub operator: String,
     /// Value being aggregated.
     pub value: Box<Value>,
+    /// Extra arguments to the operator, evaluated outside of the subquery.
+    pub args: Vec<Value>,
     /// List of clauses to treat as a subquery for the aggregate.
     pub subquery: Vec<Clause>,
 }
 
-/// An external import from a static JSON dataset.
+/// A monotone lattice operation combining values of a field through recursion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lattice {
+    /// Name of the lattice operator, such as `min`, `max`, or `union`.
+    pub operator: String,
+    /// Value being combined into the lattice.
+    pub value: Box<Value>,
+}
+
+/// Several aggregate operations computed in a single pass over a subquery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultiAggregate {
+    /// Aggregates to compute, each bound to a local variable.
+    pub outputs: Vec<AggregateOutput>,
+    /// List of clauses to treat as a subquery for all of the aggregates.
+    pub subquery: Vec<Clause>,
+}
+
+/// One output of a multi-aggregate, such as `total = sum[mpg]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregateOutput {
+    /// Name of the local variable bound to the result.
+    pub name: String,
+    /// Name of the aggregate operator, such as `min` or `sum`.
+    pub operator: String,
+    /// Value being aggregated.
+    pub value: Value,
+    /// Extra arguments to the operator, evaluated outside of the subquery.
+    pub args: Vec<Value>,
+}
+
+/// An external import from a static dataset.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Import {
     /// Name of the relation being imported.
     pub name: String,
     /// Source URI of the import.
     pub uri: String,
+    /// Format of the dataset, if given explicitly after the `as` keyword.
+    /// Otherwise, the format is inferred from the URL at runtime.
+    pub format: Option<ImportFormat>,
+    /// Expected SHA-256 hash of the dataset in hex, given after the `sha256`
+    /// keyword, which is checked before the data is used.
+    pub sha256: Option<String>,
+    /// Fields selected from each imported record, mapped to the names of their
+    /// source columns. If not given, every field is imported as-is.
+    pub fields: Option<BTreeMap<String, String>>,
+}
+
+/// Format of an imported dataset, such as `csv(delimiter: ";")`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportFormat {
+    /// Name of the format, such as `json` or `csv`.
+    pub name: String,
+    /// Options passed to the loader for this format.
+    pub options: BTreeMap<String, Value>,
+}
+
+/// A module of rules from another file, included with the `use` keyword.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Use {
+    /// Path of the module, relative to the file that uses it.
+    pub path: String,
+    /// Namespace of the module's relations, if given after the `as` keyword.
+    pub alias: Option<String>,
+}
+
+/// An assertion that two values are equal, such as
+/// `assert count[1] { bad(x) } = 0.`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assert {
+    /// Value being checked, usually an aggregate over a subquery.
+    pub left: Value,
+    /// Value that the left side is expected to equal.
+    pub right: Value,
+}
+
+/// A named block of rules and assertions, written as `test "name" { ... }`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Test {
+    /// Name of the test, shown when reporting its results.
+    pub name: String,
+    /// Rules added to the program for this test only, such as sample facts.
+    pub rules: Vec<Rule>,
+    /// Assertions checked against the results of the program and its rules.
+    pub asserts: Vec<Assert>,
+}
+
+/// A custom aggregate operator, declared with a JavaScript reducer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomAggregate {
+    /// Name of the aggregate operator.
+    pub name: String,
+    /// Raw JavaScript function from an array of values to their aggregate.
+    pub reducer: String,
 }
 
 impl Value {
     /// Returns all relations referenced by this value.
     pub fn deps(&self) -> BTreeSet<String> {
+        self.facts()
+            .into_iter()
+            .map(|fact| fact.name.clone())
+            .collect()
+    }
+
+    /// Returns all facts in subqueries nested inside of this value.
+    pub fn facts(&self) -> Vec<&Fact> {
         match self {
             Value::Aggregate(aggregate) => {
-                let mut deps: BTreeSet<_> = aggregate
+                let mut facts: Vec<_> = aggregate
                     .subquery
                     .iter()
-                    .flat_map(|clause| clause.deps())
+                    .flat_map(|clause| clause.facts())
                     .collect();
-                deps.extend(aggregate.value.deps());
-                deps
+                facts.extend(aggregate.value.facts());
+                facts.extend(aggregate.args.iter().flat_map(|arg| arg.facts()));
+                facts
             }
-            _ => BTreeSet::new(),
+            Value::Lattice(lattice) => lattice.value.facts(),
+            Value::List(items) => items.iter().flat_map(|item| item.facts()).collect(),
+            Value::Record(fields) => fields.values().flat_map(|field| field.facts()).collect(),
+            Value::Binary(_, lhs, rhs) => lhs.facts().into_iter().chain(rhs.facts()).collect(),
+            Value::Unary(_, value) => value.facts(),
+            Value::Call(_, args) => args.iter().flat_map(|arg| arg.facts()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Rename the relations referenced in subqueries of this value, for each
+    /// name that `rename` maps to a new name.
+    pub fn rename_relations(&mut self, rename: &dyn Fn(&str) -> Option<String>) {
+        match self {
+            Value::List(items) | Value::Call(_, items) => {
+                for item in items {
+                    item.rename_relations(rename);
+                }
+            }
+            Value::Record(fields) => {
+                for field in fields.values_mut() {
+                    field.rename_relations(rename);
+                }
+            }
+            Value::Aggregate(aggregate) => {
+                aggregate.value.rename_relations(rename);
+                for arg in &mut aggregate.args {
+                    arg.rename_relations(rename);
+                }
+                for clause in &mut aggregate.subquery {
+                    clause.rename_relations(rename);
+                }
+            }
+            Value::Lattice(lattice) => lattice.value.rename_relations(rename),
+            Value::Binary(_, lhs, rhs) => {
+                lhs.rename_relations(rename);
+                rhs.rename_relations(rename);
+            }
+            Value::Unary(_, value) => value.rename_relations(rename),
+            Value::Id(_) | Value::Literal(_) | Value::Expr(_) => (),
+        }
+    }
+
+    /// Returns the identifiers that this value binds when used as a pattern,
+    /// including those nested inside of lists and records.
+    pub fn pattern_vars(&self) -> Vec<&str> {
+        match self {
+            Value::Id(id) => vec![id],
+            Value::List(items) => items.iter().flat_map(|item| item.pattern_vars()).collect(),
+            Value::Record(fields) => fields
+                .values()
+                .flat_map(|field| field.pattern_vars())
+                .collect(),
+            _ => Vec::new(),
         }
     }
 }
@@ -105,46 +361,230 @@ impl Value {
 impl Clause {
     /// Returns all relations referenced by this clause.
     pub fn deps(&self) -> BTreeSet<String> {
+        self.facts()
+            .into_iter()
+            .map(|fact| fact.name.clone())
+            .collect()
+    }
+
+    /// Returns all facts in this clause, including those in subqueries.
+    pub fn facts(&self) -> Vec<&Fact> {
         match self {
             Clause::Fact(fact) => {
-                let mut deps = BTreeSet::new();
-                deps.insert(fact.name.clone());
+                let mut facts = vec![fact];
                 for value in fact.props.values() {
-                    deps.extend(value.deps());
+                    facts.extend(value.facts());
                 }
-                deps
+                facts
             }
-            Clause::Expr(_) => BTreeSet::new(),
-            Clause::Binding(_, value) => value.deps(),
+            Clause::Expr(_) => Vec::new(),
+            Clause::Condition(value) => value.facts(),
+            Clause::Binding(_, value) => value.facts(),
+            Clause::MultiAggregate(multi) => {
+                let mut facts: Vec<_> = multi
+                    .subquery
+                    .iter()
+                    .flat_map(|clause| clause.facts())
+                    .collect();
+                for output in &multi.outputs {
+                    facts.extend(output.value.facts());
+                    facts.extend(output.args.iter().flat_map(|arg| arg.facts()));
+                }
+                facts
+            }
+            Clause::Disjunction(branches) => branches
+                .iter()
+                .flatten()
+                .flat_map(|clause| clause.facts())
+                .collect(),
+        }
+    }
+
+    /// Rename the relations referenced in this clause, for each name that
+    /// `rename` maps to a new name.
+    pub fn rename_relations(&mut self, rename: &dyn Fn(&str) -> Option<String>) {
+        match self {
+            Clause::Fact(fact) => fact.rename_relations(rename),
+            Clause::Expr(_) => (),
+            Clause::Condition(value) | Clause::Binding(_, value) => value.rename_relations(rename),
+            Clause::MultiAggregate(multi) => {
+                for output in &mut multi.outputs {
+                    output.value.rename_relations(rename);
+                    for arg in &mut output.args {
+                        arg.rename_relations(rename);
+                    }
+                }
+                for clause in &mut multi.subquery {
+                    clause.rename_relations(rename);
+                }
+            }
+            Clause::Disjunction(branches) => {
+                for clause in branches.iter_mut().flatten() {
+                    clause.rename_relations(rename);
+                }
+            }
+        }
+    }
+}
+
+/// Expand the disjunctions in a list of clauses, returning one list of clauses
+/// for each combination of branches. Subqueries are left unchanged.
+pub fn expand_clauses(clauses: &[Clause]) -> Vec<Vec<Clause>> {
+    let mut expanded = vec![Vec::new()];
+    for clause in clauses {
+        match clause {
+            Clause::Disjunction(branches) => {
+                let alternatives: Vec<_> = branches
+                    .iter()
+                    .flat_map(|branch| expand_clauses(branch))
+                    .collect();
+                expanded = expanded
+                    .iter()
+                    .flat_map(|prefix| {
+                        alternatives.iter().map(move |alternative| {
+                            let mut clauses = prefix.clone();
+                            clauses.extend(alternative.iter().cloned());
+                            clauses
+                        })
+                    })
+                    .collect();
+            }
+            _ => {
+                for clauses in &mut expanded {
+                    clauses.push(clause.clone());
+                }
+            }
+        }
+    }
+    expanded
+}
+
+impl Fact {
+    /// Rename this relation and those referenced in its values, for each name
+    /// that `rename` maps to a new name.
+    pub fn rename_relations(&mut self, rename: &dyn Fn(&str) -> Option<String>) {
+        if let Some(name) = rename(&self.name) {
+            self.name = name;
+        }
+        for value in self.props.values_mut() {
+            value.rename_relations(rename);
+        }
+    }
+}
+
+impl Rule {
+    /// Returns all relations referenced by the clauses or goal of this rule.
+    pub fn deps(&self) -> BTreeSet<String> {
+        let mut deps: BTreeSet<String> = self
+            .clauses
+            .iter()
+            .flat_map(|clause| clause.deps())
+            .collect();
+        deps.extend(self.goal.props.values().flat_map(|value| value.deps()));
+        deps
+    }
+
+    /// Rename the relations in the goal and clauses of this rule, for each
+    /// name that `rename` maps to a new name.
+    pub fn rename_relations(&mut self, rename: &dyn Fn(&str) -> Option<String>) {
+        self.goal.rename_relations(rename);
+        for clause in &mut self.clauses {
+            clause.rename_relations(rename);
         }
     }
+
+    /// Split this rule into equivalent rules without disjunctions in its body,
+    /// one for each combination of branches.
+    pub fn expand(&self) -> Vec<Rule> {
+        expand_clauses(&self.clauses)
+            .into_iter()
+            .map(|clauses| Rule {
+                goal: self.goal.clone(),
+                clauses,
+                origin: self.origin.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Origin {
+    /// Returns whether the rule was written directly in the main program.
+    pub fn is_main(&self) -> bool {
+        self.module.is_none() && self.expansion.is_none()
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(expansion) = &self.expansion {
+            parts.push(format!(
+                "template \"{}\" declared at {:?} and instantiated at {:?}",
+                expansion.template, expansion.declaration, expansion.instance,
+            ));
+        }
+        if let Some(module) = &self.module {
+            parts.push(format!("module \"{}\"", module));
+        }
+        if parts.is_empty() {
+            parts.push("main program".into());
+        }
+        write!(f, "{}", parts.join(" of "))
+    }
+}
+
+impl Use {
+    /// Returns the namespace that the module's relations are referenced with,
+    /// such as `graph` in `graph.path(x, y)`. Without an alias, this is the
+    /// file name of the module, with invalid characters replaced.
+    pub fn namespace(&self) -> String {
+        if let Some(alias) = &self.alias {
+            return alias.clone();
+        }
+        let name = self.path.rsplit('/').next().unwrap_or_default();
+        let stem = match name.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem,
+            _ => name,
+        };
+        let mut namespace: String = stem
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        if !namespace.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            namespace.insert(0, '_');
+        }
+        namespace
+    }
 }
 
 impl Program {
-    /// Returns the names of all relations produced by this program.
-    pub fn results(&self) -> BTreeSet<String> {
+    /// Returns the names of all relations produced by rules in this program.
+    pub fn relations(&self) -> BTreeSet<String> {
         self.rules
             .iter()
             .map(|rule| rule.goal.name.clone())
             .collect()
     }
 
+    /// Returns the names of relations and imports that this program makes
+    /// visible to other cells, according to `export` and `private`.
+    pub fn results(&self) -> BTreeSet<String> {
+        self.relations()
+            .into_iter()
+            .chain(self.imports())
+            .filter(|name| self.exports.is_empty() || self.exports.contains(name))
+            .filter(|name| !self.private.contains(name))
+            .collect()
+    }
+
     /// Returns the names of all external relations that this program uses.
     pub fn deps(&self) -> BTreeSet<String> {
-        let results = self.results();
+        let relations = self.relations();
         let imports = self.imports();
         self.rules
             .iter()
-            .flat_map(|rule| {
-                let mut deps: BTreeSet<String> = rule
-                    .clauses
-                    .iter()
-                    .flat_map(|clause| clause.deps())
-                    .collect();
-                deps.extend(rule.goal.props.values().flat_map(|value| value.deps()));
-                deps
-            })
-            .filter(|name| !results.contains(name) && !imports.contains(name))
+            .flat_map(|rule| rule.deps())
+            .filter(|name| !relations.contains(name) && !imports.contains(name))
             .collect()
     }
 
@@ -4,25 +4,56 @@ use std::{
     collections::{BTreeMap, BTreeSet},
     fmt::Display,
     rc::Rc,
+    str::FromStr,
+    time::Duration,
 };
 
 use rpds::{RedBlackTreeMap, RedBlackTreeSet};
 use thiserror::Error;
 
-use crate::ast::{Aggregate, Clause, Literal, Program, Rule, Value};
+use crate::{
+    ast::{
+        expand_clauses, Aggregate, BinaryOp, Clause, ImportFormat, Literal, MultiAggregate, Origin,
+        Program, Rule, UnaryOp, Value,
+    },
+    lint,
+    parser::Grammar,
+    resolve::{self, ImportResolver, Resolvers},
+    runtime,
+};
 
 const VAR_DEPS: &str = "__percival_deps";
 const VAR_IMMUTABLE: &str = "__percival.Immutable";
 const VAR_LOAD: &str = "__percival.load";
 const VAR_AGGREGATES: &str = "__percival.aggregates";
+const VAR_CUSTOM_AGGREGATES: &str = "__percival_custom_aggregates";
+const VAR_FUNCTIONS: &str = "__percival.functions";
 const VAR_IMPORTS: &str = "__percival_imports";
 
 const VAR_FIRST_ITERATION: &str = "__percival_first_iteration";
 const VAR_OBJ: &str = "__percival_obj";
 const VAR_GOAL: &str = "__percival_goal";
-
-/// List of aggregate operators. Keep this in sync with `worker.ts`.
-const OPERATORS: [&str; 5] = ["count", "sum", "mean", "min", "max"];
+const VAR_GOAL_KEY: &str = "__percival_goal_key";
+const VAR_PREV: &str = "__percival_prev";
+const VAR_LHS: &str = "__percival_lhs";
+const VAR_RHS: &str = "__percival_rhs";
+const VAR_ARG: &str = "__percival_arg";
+const VAR_JOINED: &str = "__percival_joined";
+
+const VAR_KEY: &str = "__percival_key";
+const VAR_CANON: &str = "__percival_canon";
+const VAR_PUSH: &str = "__percival_push";
+
+const VAR_ITERATIONS: &str = "__percival_iterations";
+const VAR_DEADLINE: &str = "__percival_deadline";
+const VAR_LIMIT_ERROR: &str = "__percival_limit_error";
+const VAR_GROWING: &str = "__percival_growing";
+const VAR_INSERTIONS: &str = "__percival_insertions";
+const VAR_CHECK_LIMITS: &str = "__percival_check_limits";
+
+/// Number of goal insertions between checks of the tuple and time limits
+/// within an iteration.
+const LIMITS_CHECK_INTERVAL: u32 = 1024;
 
 /// An error during code generation.
 #[derive(Error, Debug)]
@@ -39,26 +70,206 @@ pub enum Error {
     #[error("Imported relation \"{0}\" cannot be used as the goal of a rule")]
     GoalImportConflict(String),
 
+    /// A program was compiled without first linking the modules it uses.
+    #[error("Module \"{0}\" must be linked before compiling")]
+    UnlinkedModule(String),
+
+    /// A relation was marked as both exported and private.
+    #[error("Relation \"{0}\" cannot be both exported and private")]
+    VisibilityConflict(String),
+
     /// Import protocol not understood in directive.
     #[error("Unknown import protocol \"{0}\"")]
     UnknownProtocol(String),
 
+    /// Import format not understood in directive.
+    #[error("Unknown import format \"{0}\", expected json, jsonl, csv, tsv, or columnar")]
+    UnknownFormat(String),
+
+    /// An import format was given an unknown option, or an invalid value.
+    #[error("Invalid option \"{1}\" for import format \"{0}\"")]
+    InvalidFormatOption(String, String),
+
+    /// A rule used a field that was not selected from an imported relation.
+    #[error("Field \"{1}\" is not selected by the import of \"{0}\"")]
+    UnselectedField(String, String),
+
+    /// A resolver failed to resolve the URI of an import.
+    #[error("Could not resolve import \"{0}\": {1}")]
+    ImportResolution(String, String),
+
     /// Two conflicting variables were defined with the same name.
     #[error("Conflicting declaration of variable \"{0}\"")]
     DuplicateVariable(String),
 
     /// Unknown aggregate operator was referenced.
-    #[error("Aggregate operator \"{0}\" is not in {OPERATORS:?}")]
+    #[error("Aggregate operator \"{0}\" is not built in or declared")]
     UnknownAggregate(String),
 
-    /// Aggregate references relation that is declared in this cell.
-    #[error("Relation \"{0}\" is queried in the same cell that it is declared")]
+    /// A custom aggregate was declared twice.
+    #[error("Aggregate operator \"{0}\" is declared more than once")]
+    DuplicateAggregate(String),
+
+    /// A custom aggregate has the same name as a built-in operator.
+    #[error("Aggregate operator \"{0}\" shadows a built-in aggregate")]
+    ShadowedAggregate(String),
+
+    /// An aggregate was given the wrong number of extra arguments.
+    #[error("Aggregate operator \"{0}\" takes {1} extra argument(s), but {2} were given")]
+    AggregateArity(String, usize, usize),
+
+    /// Unknown function was called in an expression.
+    #[error("Function \"{0}\" is not built in")]
+    UnknownFunction(String),
+
+    /// A function was called with the wrong number of arguments.
+    #[error("Function \"{0}\" takes {1} argument(s), but {2} were given")]
+    FunctionArity(String, usize, usize),
+
+    /// Aggregate references relation that recursively depends on it.
+    #[error("Relation \"{0}\" is aggregated over in its own recursive definition")]
     CircularReference(String),
+
+    /// Unknown lattice operator was used in the goal of a rule.
+    #[error("Lattice operator \"{0}\" is not one of min, max, or union")]
+    UnknownLattice(String),
+
+    /// Rules for the same relation disagree on its lattice field.
+    #[error("Rules for relation \"{0}\" must all use the same lattice field and operator")]
+    LatticeConflict(String),
+
+    /// A variable bound in only some branches of a disjunction was read later.
+    #[error("Variable \"{0}\" is bound in only some branches of a disjunction, but used after it")]
+    PartialDisjunction(String),
+
+    /// A JavaScript expression that is not a native expression was found in
+    /// sandboxed mode.
+    #[error("JavaScript expression `{0}` is not allowed in sandboxed mode")]
+    SandboxedExpression(String),
+
+    /// A custom aggregate was declared in sandboxed mode.
+    #[error("Custom aggregate \"{0}\" cannot be declared in sandboxed mode")]
+    SandboxedAggregate(String),
+
+    /// An error in a rule that was not written in the main program.
+    #[error("{0} (in {1})")]
+    Located(Box<Error>, Origin),
 }
 
 /// Result returned by the compiler.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Data structures used to represent relations in the generated code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Persistent collections from Immutable.js, provided by the runtime.
+    #[default]
+    Immutable,
+
+    /// Plain JavaScript `Map` objects, keyed by canonical tuple strings.
+    ///
+    /// This backend has no runtime dependencies other than the loader and
+    /// aggregates, and it avoids the overhead of persistent data structures.
+    Native,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "immutable" => Ok(Backend::Immutable),
+            "native" => Ok(Backend::Native),
+            _ => Err(format!(
+                "Unknown backend \"{}\", expected \"immutable\" or \"native\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Options controlling how a program is compiled, built with chained setters.
+///
+/// ```
+/// use percival::codegen::{Backend, CompileOptions};
+///
+/// let options = CompileOptions::new().backend(Backend::Native);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CompileOptions {
+    backend: Backend,
+    max_iterations: Option<u64>,
+    max_tuples: Option<u64>,
+    time_limit: Option<Duration>,
+    aggregates: BTreeMap<String, String>,
+    sandbox: bool,
+    resolvers: Resolvers,
+}
+
+impl CompileOptions {
+    /// Construct a new set of options with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the data structures used to represent relations.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Abort evaluation after this many iterations of the loop computing any
+    /// one group of mutually recursive relations.
+    pub fn max_iterations(mut self, limit: u64) -> Self {
+        self.max_iterations = Some(limit);
+        self
+    }
+
+    /// Abort evaluation when any relation grows past this many tuples.
+    pub fn max_tuples(mut self, limit: u64) -> Self {
+        self.max_tuples = Some(limit);
+        self
+    }
+
+    /// Abort evaluation when the main loop runs for longer than this duration.
+    pub fn time_limit(mut self, limit: Duration) -> Self {
+        self.time_limit = Some(limit);
+        self
+    }
+
+    /// Register a custom aggregate operator, implemented by a JavaScript
+    /// function that takes an array of values and returns their aggregate.
+    pub fn aggregate(mut self, name: impl Into<String>, reducer: impl Into<String>) -> Self {
+        self.aggregates.insert(name.into(), reducer.into());
+        self
+    }
+
+    /// Forbid raw JavaScript in the program, so that it is safe to evaluate
+    /// untrusted code.
+    ///
+    /// Expressions between backticks are only accepted if they are also valid
+    /// native expressions, and are then compiled as such. Custom aggregates
+    /// cannot be declared in the program, but may still be registered through
+    /// these options.
+    pub fn sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Register a resolver for import URIs with a scheme, such as `"file"`.
+    ///
+    /// This takes precedence over the built-in resolvers for `http`, `https`,
+    /// `gh`, and `npm` URIs.
+    pub fn resolver(
+        mut self,
+        scheme: impl Into<String>,
+        resolver: impl ImportResolver + 'static,
+    ) -> Self {
+        self.resolvers.insert(scheme.into(), Rc::new(resolver));
+        self
+    }
+}
+
 /// An index created on a subset of relation fields.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Index {
@@ -89,6 +300,30 @@ pub enum VarId {
 
     /// A bound local variable in Datalog.
     Var(String),
+
+    /// Best tuples of lattice relations, keyed by their other fields.
+    Best(String),
+}
+
+/// Operator combining the values of a lattice field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LatticeOp {
+    Min,
+    Max,
+    Union,
+}
+
+impl FromStr for LatticeOp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "min" => Ok(LatticeOp::Min),
+            "max" => Ok(LatticeOp::Max),
+            "union" => Ok(LatticeOp::Union),
+            _ => Err(Error::UnknownLattice(s.into())),
+        }
+    }
 }
 
 /// Context storing mappings of [`VarId`] to their JavaScript identifiers.
@@ -101,16 +336,22 @@ struct Context {
     deps: Rc<BTreeSet<String>>,
     results: Rc<BTreeSet<String>>,
     imports: Rc<BTreeSet<String>>,
+    aggregates: Rc<BTreeMap<String, String>>,
+    lattices: Rc<BTreeMap<String, (String, LatticeOp)>>,
+    backend: Backend,
     counter: u32,
 }
 
 impl Context {
-    fn new(prog: &Program) -> Self {
+    fn new(prog: &Program, backend: Backend) -> Self {
         Context {
             map: RedBlackTreeMap::new(),
             deps: Rc::new(prog.deps()),
-            results: Rc::new(prog.results()),
+            results: Rc::new(prog.relations()),
             imports: Rc::new(prog.imports()),
+            aggregates: Rc::new(BTreeMap::new()),
+            lattices: Rc::new(BTreeMap::new()),
+            backend,
             counter: 0,
         }
     }
@@ -119,6 +360,11 @@ impl Context {
     fn gensym(&mut self, key: &str) -> String {
         let counter = self.counter;
         self.counter += 1;
+        // Relations from modules have qualified names like `graph.path`.
+        let key: String = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
         format!("__percival_{}_{}", key, counter)
     }
 
@@ -145,25 +391,320 @@ impl Context {
     fn is_bound(&self, value: &Value) -> bool {
         match value {
             Value::Id(id) => self.map.contains_key(&VarId::Var(id.clone())),
-            Value::Literal(_) | Value::Expr(_) | Value::Aggregate(_) => true,
+            Value::List(items) => items.iter().all(|item| self.is_bound(item)),
+            Value::Record(fields) => fields.values().all(|field| self.is_bound(field)),
+            Value::Literal(_)
+            | Value::Expr(_)
+            | Value::Aggregate(_)
+            | Value::Lattice(_)
+            | Value::Binary(..)
+            | Value::Unary(..)
+            | Value::Call(..) => true,
         }
     }
 }
 
 /// Generates a JavaScript function body that evaluates the program.
 pub fn compile(prog: &Program) -> Result<String> {
-    let ctx = make_global_context(prog)?;
+    compile_with(prog, &CompileOptions::default())
+}
+
+/// Generates a JavaScript function body, with custom compilation options.
+pub fn compile_with(prog: &Program, options: &CompileOptions) -> Result<String> {
+    let prog = &normalize(prog, options)?;
+    let ctx = make_global_context(prog, options)?;
     let code = [
-        cmp_imports(prog)?,
+        options.backend.prelude(),
+        cmp_custom_aggregates(&ctx),
+        cmp_imports(prog, options)?,
         cmp_decls(&ctx)?,
-        cmp_main_loop(&ctx, prog)?,
-        cmp_output(&ctx)?,
+        cmp_main_loop(&ctx, prog, options)?,
+        cmp_output(&ctx, prog)?,
     ];
     Ok(code.join("\n"))
 }
 
-fn make_global_context(prog: &Program) -> Result<Context> {
-    let mut ctx = Context::new(prog);
+/// Rewrite a program into an equivalent one without disjunctions in the bodies
+/// of its rules, by splitting each rule into one rule for each branch.
+///
+/// Disjunctions within aggregate subqueries are kept, since those are compiled
+/// to a separate loop for each branch.
+fn normalize(prog: &Program, options: &CompileOptions) -> Result<Program> {
+    if let Some(module) = prog.uses.first() {
+        return Err(Error::UnlinkedModule(module.path.clone()));
+    }
+    if let Some(name) = prog.exports.intersection(&prog.private).next() {
+        return Err(Error::VisibilityConflict(name.clone()));
+    }
+
+    let mut prog = prog.clone();
+    if options.sandbox {
+        if let Some(custom) = prog.aggregates.first() {
+            return Err(Error::SandboxedAggregate(custom.name.clone()));
+        }
+        let grammar = Grammar::new();
+        for rule in &mut prog.rules {
+            let origin = rule.origin.clone();
+            locate(&origin, || {
+                sandbox_clauses(&grammar, &mut rule.clauses)?;
+                rule.goal
+                    .props
+                    .values_mut()
+                    .try_for_each(|value| sandbox_value(&grammar, value))
+            })?;
+        }
+    }
+
+    for import in &prog.imports {
+        if let Some(fields) = &import.fields {
+            check_selected_fields(&prog, &import.name, fields)?;
+        }
+    }
+
+    for rule in &prog.rules {
+        locate(&rule.origin, || {
+            let mut scope = Scope::default();
+            check_clauses(&mut scope, &rule.clauses)?;
+            rule.goal
+                .props
+                .values()
+                .try_for_each(|value| check_value(&scope, value))
+        })?;
+    }
+    Ok(Program {
+        rules: prog.rules.iter().flat_map(Rule::expand).collect(),
+        ..prog
+    })
+}
+
+/// Run a step of compiling a rule, adding its origin to any error.
+fn locate<T>(origin: &Origin, step: impl FnOnce() -> Result<T>) -> Result<T> {
+    step().map_err(|err| {
+        if origin.is_main() {
+            err
+        } else {
+            Error::Located(Box::new(err), origin.clone())
+        }
+    })
+}
+
+/// Replace JavaScript expressions between backticks with the equivalent
+/// native expressions, failing if they cannot be parsed as such.
+fn sandbox_clauses(grammar: &Grammar, clauses: &mut [Clause]) -> Result<()> {
+    for clause in clauses {
+        match clause {
+            Clause::Fact(fact) => {
+                for value in fact.props.values_mut() {
+                    sandbox_value(grammar, value)?;
+                }
+            }
+            Clause::Expr(expr) => *clause = Clause::Condition(sandbox_expr(grammar, expr)?),
+            Clause::Condition(value) | Clause::Binding(_, value) => sandbox_value(grammar, value)?,
+            Clause::MultiAggregate(multi) => {
+                for output in &mut multi.outputs {
+                    sandbox_value(grammar, &mut output.value)?;
+                    for arg in &mut output.args {
+                        sandbox_value(grammar, arg)?;
+                    }
+                }
+                sandbox_clauses(grammar, &mut multi.subquery)?;
+            }
+            Clause::Disjunction(branches) => {
+                for branch in branches {
+                    sandbox_clauses(grammar, branch)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn sandbox_value(grammar: &Grammar, value: &mut Value) -> Result<()> {
+    match value {
+        Value::Expr(expr) => *value = sandbox_expr(grammar, expr)?,
+        Value::List(items) | Value::Call(_, items) => {
+            for item in items {
+                sandbox_value(grammar, item)?;
+            }
+        }
+        Value::Record(fields) => {
+            for field in fields.values_mut() {
+                sandbox_value(grammar, field)?;
+            }
+        }
+        Value::Aggregate(aggregate) => {
+            sandbox_value(grammar, &mut aggregate.value)?;
+            for arg in &mut aggregate.args {
+                sandbox_value(grammar, arg)?;
+            }
+            sandbox_clauses(grammar, &mut aggregate.subquery)?;
+        }
+        Value::Lattice(lattice) => sandbox_value(grammar, &mut lattice.value)?,
+        Value::Binary(_, lhs, rhs) => {
+            sandbox_value(grammar, lhs)?;
+            sandbox_value(grammar, rhs)?;
+        }
+        Value::Unary(_, value) => sandbox_value(grammar, value)?,
+        Value::Id(_) | Value::Literal(_) => (),
+    }
+    Ok(())
+}
+
+fn sandbox_expr(grammar: &Grammar, expr: &str) -> Result<Value> {
+    grammar
+        .parse_value(expr)
+        .map_err(|_| Error::SandboxedExpression(expr.into()))
+}
+
+/// Check that rules only use the fields selected from an imported relation.
+fn check_selected_fields(
+    prog: &Program,
+    name: &str,
+    fields: &BTreeMap<String, String>,
+) -> Result<()> {
+    let facts = prog.rules.iter().flat_map(|rule| {
+        let clauses = rule.clauses.iter().flat_map(Clause::facts);
+        clauses.chain(rule.goal.props.values().flat_map(Value::facts))
+    });
+    for fact in facts.filter(|fact| fact.name == name) {
+        if let Some(key) = fact.props.keys().find(|key| !fields.contains_key(*key)) {
+            return Err(Error::UnselectedField(name.into(), key.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Variables in scope while checking a list of clauses for disjunctions.
+#[derive(Clone, Default)]
+struct Scope {
+    /// Variables bound in every branch of the clauses so far.
+    bound: BTreeSet<String>,
+    /// Variables bound in only some branches of an earlier disjunction.
+    partial: BTreeSet<String>,
+}
+
+impl Scope {
+    fn bind(&mut self, name: &str) {
+        self.partial.remove(name);
+        self.bound.insert(name.into());
+    }
+
+    /// Check that none of the given variables are read while partially bound.
+    fn read(&self, vars: BTreeSet<String>) -> Result<()> {
+        match vars.into_iter().find(|var| self.partial.contains(var)) {
+            Some(var) => Err(Error::PartialDisjunction(var)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn check_clauses(scope: &mut Scope, clauses: &[Clause]) -> Result<()> {
+    for clause in clauses {
+        match clause {
+            Clause::Fact(fact) => {
+                // Variables in patterns are joined on if bound, or bound otherwise,
+                // so they are only read in nested expressions and aggregates.
+                for value in fact.props.values() {
+                    check_pattern(scope, value)?;
+                }
+                for value in fact.props.values() {
+                    for var in value.pattern_vars() {
+                        scope.bind(var);
+                    }
+                }
+            }
+            Clause::Expr(expr) => scope.read(lint::js_identifiers(expr))?,
+            Clause::Condition(value) => check_value(scope, value)?,
+            Clause::Binding(name, value) => {
+                check_value(scope, value)?;
+                scope.bind(name);
+            }
+            Clause::MultiAggregate(multi) => {
+                let mut inner = scope.clone();
+                check_clauses(&mut inner, &multi.subquery)?;
+                for output in &multi.outputs {
+                    check_value(&inner, &output.value)?;
+                    for arg in &output.args {
+                        check_value(scope, arg)?;
+                    }
+                }
+                for output in &multi.outputs {
+                    scope.bind(&output.name);
+                }
+            }
+            Clause::Disjunction(branches) => {
+                let mut bound_sets = Vec::new();
+                for branch in branches {
+                    let mut inner = scope.clone();
+                    check_clauses(&mut inner, branch)?;
+                    bound_sets.push(inner.bound);
+                }
+                let all: BTreeSet<_> = bound_sets.iter().flatten().cloned().collect();
+                for var in all {
+                    if bound_sets.iter().all(|bound| bound.contains(&var)) {
+                        scope.bind(&var);
+                    } else {
+                        scope.partial.insert(var);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_pattern(scope: &Scope, value: &Value) -> Result<()> {
+    match value {
+        Value::Id(_) => Ok(()),
+        Value::List(items) => items.iter().try_for_each(|item| check_pattern(scope, item)),
+        Value::Record(fields) => fields
+            .values()
+            .try_for_each(|field| check_pattern(scope, field)),
+        _ => check_value(scope, value),
+    }
+}
+
+fn check_value(scope: &Scope, value: &Value) -> Result<()> {
+    scope.read(lint::value_vars(value))?;
+    match value {
+        Value::List(items) => items.iter().try_for_each(|item| check_value(scope, item)),
+        Value::Record(fields) => fields
+            .values()
+            .try_for_each(|field| check_value(scope, field)),
+        Value::Lattice(lattice) => check_value(scope, &lattice.value),
+        Value::Binary(_, lhs, rhs) => {
+            check_value(scope, lhs)?;
+            check_value(scope, rhs)
+        }
+        Value::Unary(_, value) => check_value(scope, value),
+        Value::Call(_, args) => args.iter().try_for_each(|arg| check_value(scope, arg)),
+        Value::Aggregate(aggregate) => {
+            let mut inner = scope.clone();
+            check_clauses(&mut inner, &aggregate.subquery)?;
+            check_value(&inner, &aggregate.value)
+        }
+        Value::Id(_) | Value::Literal(_) | Value::Expr(_) => Ok(()),
+    }
+}
+
+fn make_global_context(prog: &Program, options: &CompileOptions) -> Result<Context> {
+    let mut ctx = Context::new(prog, options.backend);
+
+    let mut aggregates = options.aggregates.clone();
+    for custom in &prog.aggregates {
+        if aggregates.contains_key(&custom.name) {
+            return Err(Error::DuplicateAggregate(custom.name.clone()));
+        }
+        aggregates.insert(custom.name.clone(), custom.reducer.clone());
+    }
+    if let Some(name) = aggregates
+        .keys()
+        .find(|name| runtime::aggregate(name).is_some())
+    {
+        return Err(Error::ShadowedAggregate(name.clone()));
+    }
+    ctx.aggregates = Rc::new(aggregates);
+    ctx.lattices = Rc::new(make_lattices(prog)?);
 
     if ctx.imports.len() < prog.imports.len() {
         // Some duplicate import during parsing, find and return it.
@@ -198,6 +739,11 @@ fn make_global_context(prog: &Program) -> Result<Context> {
             .add(VarId::Update(name.clone()), update_name);
     }
 
+    for name in Rc::clone(&ctx.lattices).keys() {
+        let best_name = ctx.gensym(&format!("{}_best", name));
+        ctx = ctx.add(VarId::Best(name.clone()), best_name);
+    }
+
     for index in make_indices(prog) {
         let index_name = ctx.gensym(&format!("{}_index", index.name));
         ctx = ctx.add(VarId::Index(index.clone()), index_name);
@@ -210,29 +756,122 @@ fn make_global_context(prog: &Program) -> Result<Context> {
     Ok(ctx)
 }
 
+/// Find the lattice field and operator of each relation that has one.
+fn make_lattices(prog: &Program) -> Result<BTreeMap<String, (String, LatticeOp)>> {
+    let mut goals: BTreeMap<&str, Option<(String, LatticeOp)>> = BTreeMap::new();
+    for rule in &prog.rules {
+        let mut lattice = None;
+        for (field, value) in &rule.goal.props {
+            if let Value::Lattice(l) = value {
+                lattice = Some((field.clone(), l.operator.parse()?));
+            }
+        }
+        let name = &rule.goal.name[..];
+        match goals.get(name) {
+            Some(existing) if *existing != lattice => {
+                return Err(Error::LatticeConflict(name.into()));
+            }
+            _ => {
+                goals.insert(name, lattice);
+            }
+        }
+    }
+    Ok(goals
+        .into_iter()
+        .filter_map(|(name, lattice)| Some((name.into(), lattice?)))
+        .collect())
+}
+
+/// Partition the relations computed by a program into strata.
+///
+/// Each stratum is a strongly connected component of the dependency graph, and
+/// only depends on itself and the strata before it. Strata are evaluated one at
+/// a time, so aggregates and lattices see the final contents of earlier ones.
+fn make_strata(prog: &Program) -> Vec<BTreeSet<String>> {
+    struct Tarjan<'a> {
+        graph: BTreeMap<&'a str, BTreeSet<String>>,
+        index: BTreeMap<String, usize>,
+        low: BTreeMap<String, usize>,
+        stack: Vec<String>,
+        strata: Vec<BTreeSet<String>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, name: &str) {
+            let index = self.index.len();
+            self.index.insert(name.into(), index);
+            self.low.insert(name.into(), index);
+            self.stack.push(name.into());
+
+            let deps = self.graph.get(name).cloned().unwrap_or_default();
+            for dep in &deps {
+                if !self.index.contains_key(dep) {
+                    self.visit(dep);
+                    let low = self.low[name].min(self.low[dep]);
+                    self.low.insert(name.into(), low);
+                } else if self.stack.contains(dep) {
+                    let low = self.low[name].min(self.index[dep]);
+                    self.low.insert(name.into(), low);
+                }
+            }
+
+            if self.low[name] == index {
+                let mut stratum = BTreeSet::new();
+                while let Some(member) = self.stack.pop() {
+                    let done = member == name;
+                    stratum.insert(member);
+                    if done {
+                        break;
+                    }
+                }
+                self.strata.push(stratum);
+            }
+        }
+    }
+
+    let results = prog.relations();
+    let mut graph: BTreeMap<&str, BTreeSet<String>> = BTreeMap::new();
+    for rule in &prog.rules {
+        let deps = rule.deps().into_iter().filter(|dep| results.contains(dep));
+        graph.entry(&rule.goal.name).or_default().extend(deps);
+    }
+
+    let mut tarjan = Tarjan {
+        graph,
+        index: BTreeMap::new(),
+        low: BTreeMap::new(),
+        stack: Vec::new(),
+        strata: Vec::new(),
+    };
+    for name in &results {
+        if !tarjan.index.contains_key(name) {
+            tarjan.visit(name);
+        }
+    }
+    tarjan.strata
+}
+
 fn make_indices(prog: &Program) -> BTreeSet<Index> {
-    fn walk_clause<'a>(
+    fn walk_clause(
         indices: &mut BTreeSet<Index>,
-        vars: &mut RedBlackTreeSet<&'a str>,
-        clause: &'a Clause,
+        vars: &mut RedBlackTreeSet<String>,
+        clause: &Clause,
     ) {
         match clause {
             Clause::Fact(fact) => {
                 for value in fact.props.values() {
                     walk_value(indices, vars, value);
                 }
+                let outer = vars.clone();
                 let mut bound = BTreeSet::new();
                 for (key, value) in &fact.props {
-                    match value {
-                        Value::Id(id) => {
-                            if vars.contains(&id[..]) {
-                                bound.insert(key.to_owned());
-                            } else {
-                                *vars = vars.insert(id);
-                            }
-                        }
-                        Value::Literal(_) | Value::Expr(_) | Value::Aggregate(_) => {
-                            bound.insert(key.to_owned());
+                    // Values are bound if they have no free variables in patterns.
+                    let pattern_vars = value.pattern_vars();
+                    if pattern_vars.iter().all(|var| outer.contains(*var)) {
+                        bound.insert(key.to_owned());
+                    } else {
+                        for var in pattern_vars {
+                            *vars = vars.insert(var.into());
                         }
                     }
                 }
@@ -244,27 +883,69 @@ fn make_indices(prog: &Program) -> BTreeSet<Index> {
                 }
             }
             Clause::Expr(_) => (),
-            Clause::Binding(_, value) => {
+            Clause::Condition(value) => walk_value(indices, vars, value),
+            Clause::Binding(name, value) => {
                 walk_value(indices, vars, value);
+                *vars = vars.insert(name.clone());
+            }
+            Clause::MultiAggregate(multi) => {
+                for branch in expand_clauses(&multi.subquery) {
+                    let mut inner = vars.clone();
+                    walk_clauses(indices, &mut inner, &branch);
+                    for output in &multi.outputs {
+                        walk_value(indices, &inner, &output.value);
+                    }
+                }
+                for output in &multi.outputs {
+                    for arg in &output.args {
+                        walk_value(indices, vars, arg);
+                    }
+                }
+                for output in &multi.outputs {
+                    *vars = vars.insert(output.name.clone());
+                }
             }
+            Clause::Disjunction(_) => unreachable!("disjunctions are expanded before indexing"),
         }
     }
 
-    fn walk_clauses<'a>(
+    fn walk_clauses(
         indices: &mut BTreeSet<Index>,
-        vars: &mut RedBlackTreeSet<&'a str>,
-        clauses: &'a [Clause],
+        vars: &mut RedBlackTreeSet<String>,
+        clauses: &[Clause],
     ) {
         for clause in clauses {
             walk_clause(indices, vars, clause);
         }
     }
 
-    fn walk_value(indices: &mut BTreeSet<Index>, vars: &RedBlackTreeSet<&str>, value: &Value) {
+    fn walk_value(indices: &mut BTreeSet<Index>, vars: &RedBlackTreeSet<String>, value: &Value) {
+        if let Value::List(items) | Value::Call(_, items) = value {
+            for item in items {
+                walk_value(indices, vars, item);
+            }
+        }
+        if let Value::Binary(_, lhs, rhs) = value {
+            walk_value(indices, vars, lhs);
+            walk_value(indices, vars, rhs);
+        }
+        if let Value::Unary(_, value) = value {
+            walk_value(indices, vars, value);
+        }
+        if let Value::Record(fields) = value {
+            for field in fields.values() {
+                walk_value(indices, vars, field);
+            }
+        }
         if let Value::Aggregate(aggregate) = value {
-            let mut vars = vars.clone();
-            walk_clauses(indices, &mut vars, &aggregate.subquery);
-            walk_value(indices, &vars, &aggregate.value);
+            for arg in &aggregate.args {
+                walk_value(indices, vars, arg);
+            }
+            for branch in expand_clauses(&aggregate.subquery) {
+                let mut vars = vars.clone();
+                walk_clauses(indices, &mut vars, &branch);
+                walk_value(indices, &vars, &aggregate.value);
+            }
         }
     }
 
@@ -283,25 +964,93 @@ fn make_indices(prog: &Program) -> BTreeSet<Index> {
     indices
 }
 
-fn cmp_imports(prog: &Program) -> Result<String> {
+fn cmp_custom_aggregates(ctx: &Context) -> String {
+    if ctx.aggregates.is_empty() {
+        return "".into();
+    }
+    let obj = cmp_object(ctx.aggregates.keys(), |name| {
+        Ok(format!("({})", ctx.aggregates[name]))
+    })
+    .expect("custom aggregates should compile");
+    format!("const {} = {};", VAR_CUSTOM_AGGREGATES, obj)
+}
+
+/// Compile the options object passed to the loader for an import format,
+/// checking that the options are understood by the format.
+fn cmp_import_format(format: &ImportFormat) -> Result<Vec<String>> {
+    let allowed: &[&str] = match &format.name[..] {
+        "json" | "jsonl" | "columnar" => &[],
+        "csv" | "tsv" => &["delimiter", "header", "types"],
+        _ => return Err(Error::UnknownFormat(format.name.clone())),
+    };
+    let mut fields = vec![format!("format: {}", cmp_string(&format.name))];
+    for (key, value) in &format.options {
+        let invalid = || Error::InvalidFormatOption(format.name.clone(), key.clone());
+        if !allowed.contains(&&key[..]) {
+            return Err(invalid());
+        }
+        let value = match (&key[..], value) {
+            ("delimiter", Value::Literal(Literal::String(s))) if s.chars().count() == 1 => {
+                cmp_string(s)
+            }
+            ("header", Value::Literal(Literal::Boolean(b))) => b.to_string(),
+            ("types", Value::Record(types)) => {
+                cmp_object(types.keys(), |column| match &types[column] {
+                    Value::Literal(Literal::String(ty))
+                        if matches!(&ty[..], "auto" | "string" | "number" | "boolean" | "date") =>
+                    {
+                        Ok(cmp_string(ty))
+                    }
+                    _ => Err(invalid()),
+                })?
+            }
+            _ => return Err(invalid()),
+        };
+        fields.push(format!("{}: {}", key, value));
+    }
+    Ok(fields)
+}
+
+fn cmp_imports(prog: &Program, options: &CompileOptions) -> Result<String> {
     if prog.imports.is_empty() {
         return Ok("".into());
     }
     let mut fields = Vec::new();
     for import in &prog.imports {
-        let index = import.uri.find("://");
-        let index = index.ok_or_else(|| Error::UnknownProtocol("<none>".into()))?;
-        let (protocol, address) = import.uri.split_at(index + 3);
-        let url = match protocol {
-            "http://" | "https://" => import.uri.clone(),
-            "gh://" => format!("https://cdn.jsdelivr.net/gh/{}", address),
-            "npm://" => format!("https://cdn.jsdelivr.net/npm/{}", address),
-            _ => return Err(Error::UnknownProtocol(protocol.into())),
+        let uri = &import.uri;
+        let scheme = resolve::scheme(uri).ok_or_else(|| Error::UnknownProtocol("<none>".into()))?;
+        let url = match options.resolvers.get(scheme) {
+            Some(resolver) => resolver
+                .resolve(uri)
+                .map_err(|err| Error::ImportResolution(uri.clone(), err))?,
+            None => resolve::builtin(uri).ok_or_else(|| Error::UnknownProtocol(scheme.into()))?,
         };
-        fields.push(format!(
-            "{}: await {}(\"{}\"),\n",
-            import.name, VAR_LOAD, url,
-        ));
+        let mut load_options = Vec::new();
+        if let Some(format) = &import.format {
+            load_options.extend(cmp_import_format(format)?);
+        }
+        if let Some(hash) = &import.sha256 {
+            load_options.push(format!("sha256: {}", cmp_string(hash)));
+        }
+        let mut args = cmp_string(&url);
+        if !load_options.is_empty() {
+            args += &format!(", {{{}}}", load_options.join(", "));
+        }
+        let mut load = format!("await {}({})", VAR_LOAD, args);
+        if let Some(selected) = &import.fields {
+            // Only keep the selected fields of each record, renaming them.
+            let record: Vec<_> = selected
+                .iter()
+                .map(|(field, column)| format!("{}: {}[{}]", field, VAR_OBJ, cmp_string(column)))
+                .collect();
+            load = format!(
+                "({}).map(({}) => ({{{}}}))",
+                load,
+                VAR_OBJ,
+                record.join(", "),
+            );
+        }
+        fields.push(format!("{}: {},\n", cmp_key(&import.name), load));
     }
     Ok(format!(
         "const {} = {{\n{}}};",
@@ -311,37 +1060,28 @@ fn cmp_imports(prog: &Program) -> Result<String> {
 }
 
 fn cmp_decls(ctx: &Context) -> Result<String> {
+    let backend = ctx.backend;
     let mut decls = Vec::new();
     for (id, js_name) in &ctx.map {
         match id {
             VarId::Set(name) | VarId::Update(name) => {
-                decls.push(format!("let {} = {}.Set();", js_name, VAR_IMMUTABLE));
+                decls.push(format!("let {} = {};", js_name, backend.empty_set()));
                 if ctx.deps.contains(name) || ctx.imports.contains(name) {
-                    // Initialize sets - need to move to Immutable.Map objects.
+                    // Initialize sets from the plain objects passed in.
                     let source = if ctx.deps.contains(name) {
                         VAR_DEPS
                     } else {
                         VAR_IMPORTS
                     };
-                    let init_set = format!(
-                        "
-{v} = {v}.withMutations({v} => {{
-    for (const {obj} of {source}.{name}) {{
-        {v}.add({imm}.Map({obj}));
-    }}
-}});
-",
-                        v = js_name,
-                        obj = VAR_OBJ,
-                        source = source,
-                        imm = VAR_IMMUTABLE,
-                        name = name,
-                    );
-                    decls.push(init_set.trim().into());
+                    let source = cmp_member(source, name);
+                    decls.push(backend.init_set(js_name, &source));
                 }
             }
+            VarId::Best(_) => {
+                decls.push(format!("const {} = {};", js_name, backend.mutable_map()));
+            }
             VarId::Index(index) => {
-                decls.push(format!("let {} = {}.Map();", js_name, VAR_IMMUTABLE));
+                decls.push(format!("let {} = {};", js_name, backend.empty_index()));
                 if ctx.deps.contains(&index.name) || ctx.imports.contains(&index.name) {
                     // Initialize index in the declarations.
                     let source = if ctx.deps.contains(&index.name) {
@@ -349,27 +1089,8 @@ fn cmp_decls(ctx: &Context) -> Result<String> {
                     } else {
                         VAR_IMPORTS
                     };
-                    let init_index = format!(
-                        "
-{v} = {v}.withMutations({v} => {{
-    for (const {obj} of {source}.{name}) {{
-        {v}.update({imm}.Map({bindings}), value => {{
-            if (value === undefined) value = [];
-            value.push({imm}.Map({obj}));
-            return value;
-        }});
-    }}
-}});",
-                        v = js_name,
-                        obj = VAR_OBJ,
-                        source = source,
-                        imm = VAR_IMMUTABLE,
-                        name = index.name,
-                        bindings = cmp_object(&index.bound, |field| {
-                            Ok(format!("{}.{}", VAR_OBJ, field))
-                        })?,
-                    );
-                    decls.push(init_index.trim().into());
+                    let source = cmp_member(source, &index.name);
+                    decls.push(backend.init_index(js_name, &source, &index.bound)?);
                 }
             }
             _ => (),
@@ -378,85 +1099,246 @@ fn cmp_decls(ctx: &Context) -> Result<String> {
     Ok(decls.join("\n"))
 }
 
-fn cmp_main_loop(ctx: &Context, prog: &Program) -> Result<String> {
+fn cmp_main_loop(ctx: &Context, prog: &Program, options: &CompileOptions) -> Result<String> {
+    let mut ctx = ctx.clone();
+    let mut loops = Vec::new();
+    for stratum in make_strata(prog) {
+        let stratum_ctx = Context {
+            results: Rc::new(stratum),
+            ..ctx.clone()
+        };
+        let (stratum_ctx, code) = cmp_stratum_loop(&stratum_ctx, prog, options)?;
+        ctx.counter = stratum_ctx.counter;
+        loops.push(code);
+    }
+    let main_loop = format!(
+        "
+{limits_init}
+let {first_iter};
+{loops}",
+        limits_init = cmp_limits_init(options),
+        first_iter = VAR_FIRST_ITERATION,
+        loops = loops.join("\n"),
+    );
+    Ok(main_loop.trim().into())
+}
+
+/// Compile the fixpoint loop for a single stratum, whose relations are given by
+/// `ctx.results`.
+fn cmp_stratum_loop(
+    ctx: &Context,
+    prog: &Program,
+    options: &CompileOptions,
+) -> Result<(Context, String)> {
     let updates = cmp_updates(ctx)?;
     let (ctx, new_decls) = cmp_new_decls(ctx);
-    let rules = cmp_rules(&ctx, prog)?;
+    let limits_within = cmp_limits_within(&ctx, options)?;
+    let rules = cmp_rules(&ctx, prog, options)?;
     let set_update_to_new = cmp_set_update_to_new(&ctx)?;
-    let main_loop = format!(
+    let no_updates = ctx
+        .results
+        .iter()
+        .map(|name| {
+            format!(
+                "{}.size === 0 && ",
+                ctx.get(&VarId::Update(name.into()))
+                    .expect("could not find name in main loop no_updates")
+            )
+        })
+        .collect::<Box<_>>()
+        .join("")
+        + "true";
+    let limits_check = cmp_limits_check(&ctx, options, &no_updates)?;
+    let iterations_reset = match options.max_iterations {
+        // Each stratum counts its own iterations toward the limit.
+        Some(_) => format!("{} = 0;", VAR_ITERATIONS),
+        None => "".into(),
+    };
+    let stratum_loop = format!(
         "
-let {first_iter} = true;
+{iterations_reset}
+{first_iter} = true;
 while ({first_iter} || !({no_updates})) {{
     {updates}
     {new_decls}
+    {limits_within}
     {rules}
     {set_update_to_new}
+    {limits_check}
     {first_iter} = false;
 }}",
+        iterations_reset = iterations_reset,
         first_iter = VAR_FIRST_ITERATION,
-        no_updates = ctx
-            .results
-            .iter()
-            .map(|name| format!(
-                "{}.size === 0 && ",
-                ctx.get(&VarId::Update(name.into()))
-                    .expect("could not find name in main loop no_updates")
-            ))
-            .collect::<Box<_>>()
-            .join("")
-            + "true",
+        no_updates = no_updates,
         updates = updates,
         new_decls = new_decls,
+        limits_within = limits_within,
         rules = rules,
         set_update_to_new = set_update_to_new,
+        limits_check = limits_check,
     );
-    Ok(main_loop.trim().into())
+    Ok((ctx, stratum_loop.trim().into()))
 }
 
-fn cmp_updates(ctx: &Context) -> Result<String> {
-    let mut updates = Vec::new();
+/// Compile definitions used by guards that abort evaluation when it exceeds
+/// resource limits, to be placed before all of the loops.
+///
+/// The thrown errors are annotated with `limit`, `value`, and `relation`
+/// properties for the runtime to inspect.
+fn cmp_limits_init(options: &CompileOptions) -> String {
+    if options.max_iterations.is_none()
+        && options.max_tuples.is_none()
+        && options.time_limit.is_none()
+    {
+        return "".into();
+    }
+
+    let mut init = vec![format!(
+        "
+const {err} = (limit, value, relation) => Object.assign(
+    new Error(`Evaluation exceeded the ${{limit}} limit of ${{value}}${{limit === \"time\" ? \"ms\" : \"\"}}, while computing relation \"${{relation}}\"`),
+    {{ name: \"LimitError\", limit, value, relation }},
+);
+const {growing} = updates => Object.entries(updates).reduce((a, b) => b[1].size > a[1].size ? b : a)[0];",
+        err = VAR_LIMIT_ERROR,
+        growing = VAR_GROWING,
+    )
+    .trim()
+    .to_string()];
+    if options.max_iterations.is_some() {
+        init.push(format!("let {};", VAR_ITERATIONS));
+    }
+    if let Some(limit) = options.time_limit {
+        init.push(format!(
+            "const {} = Date.now() + {};",
+            VAR_DEADLINE,
+            limit.as_millis(),
+        ));
+    }
+    if options.max_tuples.is_some() || options.time_limit.is_some() {
+        init.push(format!("let {} = 0;", VAR_INSERTIONS));
+    }
+    init.join("\n")
+}
+
+/// Compile guards at the end of each iteration of a loop, which abort the loop
+/// when it exceeds resource limits.
+fn cmp_limits_check(ctx: &Context, options: &CompileOptions, no_updates: &str) -> Result<String> {
+    let mut check = Vec::new();
+
+    let updates = cmp_object(ctx.results.iter(), |name| {
+        ctx.get(&VarId::Update(name.clone()))
+    })?;
+
+    if let Some(limit) = options.max_iterations {
+        check.push(format!(
+            "if (++{iters} >= {limit} && !({no_updates})) {{
+    throw {err}(\"iterations\", {limit}, {growing}({updates}));
+}}",
+            iters = VAR_ITERATIONS,
+            limit = limit,
+            no_updates = no_updates,
+            err = VAR_LIMIT_ERROR,
+            growing = VAR_GROWING,
+            updates = updates,
+        ));
+    }
+
+    if let Some(limit) = options.max_tuples {
+        for name in ctx.results.iter() {
+            check.push(format!(
+                "if ({set}.size + {upd}.size > {limit}) {{
+    throw {err}(\"tuples\", {limit}, {name});
+}}",
+                set = ctx.get(&VarId::Set(name.clone()))?,
+                upd = ctx.get(&VarId::Update(name.clone()))?,
+                limit = limit,
+                err = VAR_LIMIT_ERROR,
+                name = cmp_string(name),
+            ));
+        }
+    }
+
+    if let Some(limit) = options.time_limit {
+        check.push(format!(
+            "if (Date.now() > {deadline} && !({no_updates})) {{
+    throw {err}(\"time\", {millis}, {growing}({updates}));
+}}",
+            deadline = VAR_DEADLINE,
+            no_updates = no_updates,
+            err = VAR_LIMIT_ERROR,
+            millis = limit.as_millis(),
+            growing = VAR_GROWING,
+            updates = updates,
+        ));
+    }
+
+    Ok(check.join("\n"))
+}
+
+/// Compile a function that checks the tuple and time limits in the middle of
+/// an iteration, which is called periodically as goals are inserted.
+///
+/// This catches a single iteration that derives many tuples or runs for a long
+/// time, before it can finish and reach the guards at the end of the loop.
+fn cmp_limits_within(ctx: &Context, options: &CompileOptions) -> Result<String> {
+    if options.max_tuples.is_none() && options.time_limit.is_none() {
+        return Ok("".into());
+    }
+
+    let mut check = Vec::new();
+    if let Some(limit) = options.max_tuples {
+        for name in ctx.results.iter() {
+            check.push(format!(
+                "if ({set}.size + {new}.size > {limit}) {{
+    throw {err}(\"tuples\", {limit}, {name});
+}}",
+                set = ctx.get(&VarId::Set(name.clone()))?,
+                new = ctx.get(&VarId::New(name.clone()))?,
+                limit = limit,
+                err = VAR_LIMIT_ERROR,
+                name = cmp_string(name),
+            ));
+        }
+    }
+    if let Some(limit) = options.time_limit {
+        check.push(format!(
+            "if (Date.now() > {deadline}) {{
+    throw {err}(\"time\", {millis}, {growing}({new}));
+}}",
+            deadline = VAR_DEADLINE,
+            err = VAR_LIMIT_ERROR,
+            millis = limit.as_millis(),
+            growing = VAR_GROWING,
+            new = cmp_object(ctx.results.iter(), |name| {
+                ctx.get(&VarId::New(name.clone()))
+            })?,
+        ));
+    }
+    Ok(format!(
+        "const {} = () => {{\n{}\n}};",
+        VAR_CHECK_LIMITS,
+        check.join("\n"),
+    ))
+}
+
+fn cmp_updates(ctx: &Context) -> Result<String> {
+    let mut updates = Vec::new();
     for (id, js_name) in &ctx.map {
         match id {
-            VarId::Update(name) => {
-                updates.push(format!(
-                    "{v} = {v}.merge({upd});",
-                    v = ctx.get(&VarId::Set(name.into()))?,
-                    upd = js_name,
-                ));
+            VarId::Update(name) if ctx.results.contains(name) => {
+                let set_name = ctx.get(&VarId::Set(name.into()))?;
+                updates.push(ctx.backend.merge(&set_name, js_name));
             }
             VarId::Index(index) if ctx.results.contains(&index.name) => {
                 let upd_name = ctx.get(&VarId::Update(index.name.clone()))?;
                 let ind_upd_name = ctx.get(&VarId::IndexUpdate(index.clone()))?;
-                let code = format!(
-                    "
-{v} = {v}.asMutable();
-let {ind_upd} = {imm}.Map().asMutable();
-for (const {obj} of {upd}) {{
-    const key = {imm}.Map({key});
-    {v}.update(key, value => {{
-        if (value === undefined) value = [];
-        value.push({obj});
-        return value;
-    }});
-    {ind_upd}.update(key, value => {{
-        if (value === undefined) value = [];
-        value.push({obj});
-        return value;
-    }});
-}}
-{v} = {v}.asImmutable();
-{ind_upd} = {ind_upd}.asImmutable();
-",
-                    imm = VAR_IMMUTABLE,
-                    obj = VAR_OBJ,
-                    v = js_name,
-                    upd = upd_name,
-                    ind_upd = ind_upd_name,
-                    key = cmp_object(&index.bound, |field| {
-                        Ok(format!("{}.get('{}')", VAR_OBJ, field))
-                    })?,
-                );
-                updates.push(code.trim().into());
+                updates.push(ctx.backend.update_index(
+                    js_name,
+                    &ind_upd_name,
+                    &upd_name,
+                    &index.bound,
+                )?);
             }
             _ => (),
         }
@@ -469,26 +1351,24 @@ fn cmp_new_decls(ctx: &Context) -> (Context, String) {
     let mut decls = Vec::new();
     for result in Rc::clone(&ctx.results).iter() {
         let name = ctx.gensym(&format!("{}_new", result));
-        decls.push(format!(
-            "const {} = {}.Set().asMutable();",
-            name, VAR_IMMUTABLE,
-        ));
+        decls.push(format!("const {} = {};", name, ctx.backend.new_set()));
         ctx = ctx.add(VarId::New(result.clone()), name);
     }
     (ctx, decls.join("\n"))
 }
 
-fn cmp_rules(ctx: &Context, prog: &Program) -> Result<String> {
+fn cmp_rules(ctx: &Context, prog: &Program, options: &CompileOptions) -> Result<String> {
     Ok(prog
         .rules
         .iter()
-        .map(|rule| cmp_rule(ctx, rule))
+        .filter(|rule| ctx.results.contains(&rule.goal.name))
+        .map(|rule| locate(&rule.origin, || cmp_rule(ctx, rule, options)))
         .collect::<Result<Box<_>>>()?
         .join("\n"))
 }
 
 /// Compile a single Datalog rule into a collection of loops.
-fn cmp_rule(ctx: &Context, rule: &Rule) -> Result<String> {
+fn cmp_rule(ctx: &Context, rule: &Rule, options: &CompileOptions) -> Result<String> {
     let fact_positions: Vec<_> = rule
         .clauses
         .iter()
@@ -501,7 +1381,7 @@ fn cmp_rule(ctx: &Context, rule: &Rule) -> Result<String> {
 
     if fact_positions.is_empty() {
         // Will not change, so we only need to evaluate it once
-        let eval_loop = cmp_rule_incremental(ctx, rule, None)?;
+        let eval_loop = cmp_rule_incremental(ctx, rule, None, options)?;
         Ok(format!(
             "if ({first_iter}) {{\n{eval_loop}\n}}",
             first_iter = VAR_FIRST_ITERATION,
@@ -511,7 +1391,7 @@ fn cmp_rule(ctx: &Context, rule: &Rule) -> Result<String> {
         // Rule has one or more facts, so we use semi-naive evaluation
         let variants = fact_positions
             .into_iter()
-            .map(|update_position| cmp_rule_incremental(ctx, rule, Some(update_position)))
+            .map(|update_position| cmp_rule_incremental(ctx, rule, Some(update_position), options))
             .collect::<Result<Box<_>>>()?;
         Ok(variants.join("\n"))
     }
@@ -522,6 +1402,7 @@ fn cmp_rule_incremental(
     ctx: &Context,
     rule: &Rule,
     update_position: Option<usize>,
+    options: &CompileOptions,
 ) -> Result<String> {
     let mut ctx = ctx.clone();
 
@@ -531,17 +1412,23 @@ fn cmp_rule_incremental(
         clauses.push(cmp_clause(&mut ctx, clause, only_update, false)?);
     }
 
-    let goal = format!(
-        "
-const {goal} = {imm}.Map({goal_obj});
-if (!{set}.includes({goal})) {new}.add({goal});
-",
-        goal = VAR_GOAL,
-        imm = VAR_IMMUTABLE,
-        goal_obj = cmp_fields(&ctx, &rule.goal.props)?,
-        set = ctx.get(&VarId::Set(rule.goal.name.clone())).unwrap(),
-        new = ctx.get(&VarId::New(rule.goal.name.clone())).unwrap(),
-    );
+    let mut goal = match ctx.lattices.get(&rule.goal.name) {
+        Some((field, op)) => cmp_lattice_insert(&ctx, rule, field, *op)?,
+        None => ctx.backend.insert(
+            &ctx.get(&VarId::Set(rule.goal.name.clone())).unwrap(),
+            &ctx.get(&VarId::New(rule.goal.name.clone())).unwrap(),
+            &cmp_fields(&ctx, &rule.goal.props)?,
+        ),
+    };
+    if options.max_tuples.is_some() || options.time_limit.is_some() {
+        goal = format!(
+            "{}\nif (++{} % {} === 0) {}();",
+            goal.trim(),
+            VAR_INSERTIONS,
+            LIMITS_CHECK_INTERVAL,
+            VAR_CHECK_LIMITS,
+        );
+    }
 
     let mut code = String::from("{\n");
     for clause in &clauses {
@@ -553,6 +1440,55 @@ if (!{set}.includes({goal})) {new}.add({goal});
     Ok(code)
 }
 
+/// Compile statements adding a goal to a lattice relation, if it improves on
+/// the best tuple with the same values for the other fields.
+fn cmp_lattice_insert(ctx: &Context, rule: &Rule, field: &str, op: LatticeOp) -> Result<String> {
+    let backend = ctx.backend;
+    let name = &rule.goal.name;
+    let best = ctx.get(&VarId::Best(name.clone()))?;
+    let value = backend.field(VAR_GOAL, field);
+    let prev = backend.field(VAR_PREV, field);
+    let (improves, joined) = match op {
+        LatticeOp::Min => (format!("{} < {}", value, prev), VAR_GOAL.into()),
+        LatticeOp::Max => (format!("{} > {}", value, prev), VAR_GOAL.into()),
+        LatticeOp::Union => (
+            format!("!{}", backend.contains(&prev, &value)),
+            backend.with_field(
+                VAR_GOAL,
+                field,
+                &format!(
+                    "{prev_var} === undefined ? {single} : {added}",
+                    prev_var = VAR_PREV,
+                    single = backend.singleton(&value),
+                    added = backend.add(&prev, &value),
+                ),
+            ),
+        ),
+    };
+    let code = format!(
+        "
+const {goal} = {tuple};
+const {goal_key} = {key};
+const {prev_var} = {best}.get({goal_key});
+if ({prev_var} === undefined || {improves}) {{
+    const {joined_var} = {joined};
+    {best}.set({goal_key}, {joined_var});
+    {add_new}
+}}",
+        goal = VAR_GOAL,
+        tuple = backend.tuple(&cmp_fields(ctx, &rule.goal.props)?),
+        goal_key = VAR_GOAL_KEY,
+        key = backend.lattice_key(VAR_GOAL, field),
+        prev_var = VAR_PREV,
+        best = best,
+        improves = improves,
+        joined_var = VAR_JOINED,
+        joined = joined,
+        add_new = backend.add_new(&ctx.get(&VarId::New(name.clone()))?, VAR_JOINED),
+    );
+    Ok(code.trim().into())
+}
+
 fn cmp_clause(
     ctx: &mut Context,
     clause: &Clause,
@@ -565,25 +1501,31 @@ fn cmp_clause(
                 return Err(Error::CircularReference(fact.name.clone()));
             }
 
+            // Fields are only looked up by value if they are bound before this
+            // fact, while repeated variables within it are checked for equality.
+            let outer = ctx.clone();
             let mut bound_fields = BTreeMap::new();
             let mut setters = Vec::new();
             for (key, value) in &fact.props {
-                if ctx.is_bound(value) {
+                if outer.is_bound(value) {
                     bound_fields.insert(key.clone(), value.clone());
                 } else {
-                    match value {
-                        Value::Id(id) => {
-                            // Use the same name for the variable in JavaScript.
-                            let name = id.clone();
-                            setters.push(format!("const {} = {}.get('{}');", name, VAR_OBJ, key));
-                            *ctx = ctx.add(VarId::Var(id.clone()), name);
-                        }
-                        Value::Literal(_) | Value::Expr(_) | Value::Aggregate(_) => {
-                            unreachable!("literal and expression values are always bound")
-                        }
-                    }
+                    let field = ctx.backend.field(VAR_OBJ, key);
+                    cmp_pattern(ctx, &field, value, &mut setters)?;
                 }
             }
+            if let Some((field, _)) = ctx.lattices.get(&fact.name) {
+                // Skip tuples that have been superseded by a better value.
+                setters.insert(
+                    0,
+                    format!(
+                        "if ({}.get({}) !== {}) continue;",
+                        ctx.get(&VarId::Best(fact.name.clone()))?,
+                        ctx.backend.lattice_key(VAR_OBJ, field),
+                        VAR_OBJ,
+                    ),
+                );
+            }
 
             if bound_fields.is_empty() {
                 // No bound fields, just iterate over the set.
@@ -600,7 +1542,7 @@ for (const {obj} of {set}) {{
     {setters}
 ",
                     obj = VAR_OBJ,
-                    set = set,
+                    set = ctx.backend.iter(&set),
                     setters = setters.join("\n"),
                 );
                 Ok(code.trim().into())
@@ -616,15 +1558,31 @@ for (const {obj} of {set}) {{
                     VarId::IndexUpdate(index)
                 })?;
 
+                // Variables bound to null never join with other tuples, like in
+                // SQL. Use a `null` literal to match missing fields instead.
+                let mut null_checks = Vec::new();
+                for value in bound_fields.values() {
+                    if let Value::Id(id) = value {
+                        null_checks.push(format!("{} === null", ctx.get(&VarId::Var(id.clone()))?));
+                    }
+                }
+                let guard = if null_checks.is_empty() {
+                    String::new()
+                } else {
+                    format!("{} ? [] : ", null_checks.join(" || "))
+                };
+
                 let code = format!(
                     "
-for (const {obj} of {index}.get({imm}.Map({bindings})) ?? []) {{
+for (const {obj} of {guard}{index}.get({key}) ?? []) {{
     {setters}
 ",
                     obj = VAR_OBJ,
-                    imm = VAR_IMMUTABLE,
+                    guard = guard,
                     index = index,
-                    bindings = cmp_fields(ctx, &bound_fields)?,
+                    key = ctx.backend.index_key(bound_fields.keys(), |key| {
+                        cmp_value(ctx, &bound_fields[key])
+                    })?,
                     setters = setters.join("\n"),
                 );
                 Ok(code.trim().into())
@@ -636,6 +1594,11 @@ for (const {obj} of {index}.get({imm}.Map({bindings})) ?? []) {{
             Ok(format!("if ({}) {{", expr))
         }
 
+        Clause::Condition(value) => {
+            assert!(!only_update);
+            Ok(format!("if ({}) {{", cmp_value(ctx, value)?))
+        }
+
         Clause::Binding(name, value) => {
             assert!(!only_update);
             let key = VarId::Var(name.clone());
@@ -645,6 +1608,114 @@ for (const {obj} of {index}.get({imm}.Map({bindings})) ?? []) {{
             *ctx = ctx.add(VarId::Var(name.clone()), name.clone());
             Ok(format!("{{\nconst {} = {};", name, cmp_value(ctx, value)?))
         }
+
+        Clause::MultiAggregate(multi) => {
+            assert!(!only_update);
+            let value = cmp_multi_aggregate(ctx, multi)?;
+            let mut names = Vec::new();
+            for output in &multi.outputs {
+                let key = VarId::Var(output.name.clone());
+                if ctx.map.contains_key(&key) || names.contains(&&output.name) {
+                    return Err(Error::DuplicateVariable(output.name.clone()));
+                }
+                names.push(&output.name);
+            }
+            for name in &names {
+                *ctx = ctx.add(VarId::Var(name.to_string()), name.to_string());
+            }
+            Ok(format!(
+                "{{\nconst [{}] = {};",
+                names
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                value,
+            ))
+        }
+
+        Clause::Disjunction(_) => unreachable!("disjunctions are expanded before compilation"),
+    }
+}
+
+/// Compile statements matching a value against a pattern with free variables.
+///
+/// This binds the free variables in the pattern, and skips to the next tuple
+/// of the enclosing loop if the value does not have the right shape.
+fn cmp_pattern(
+    ctx: &mut Context,
+    value: &str,
+    pattern: &Value,
+    setters: &mut Vec<String>,
+) -> Result<()> {
+    let backend = ctx.backend;
+    if ctx.is_bound(pattern) {
+        let expected = cmp_value(ctx, pattern)?;
+        let mut check = backend.equals(&backend.value_from_js(value), &expected);
+        if let Value::Id(_) = pattern {
+            // Variables bound to null are not equal to anything.
+            check = format!("{} !== null && {}", expected, check);
+        }
+        setters.push(format!("if (!({})) continue;", check));
+        return Ok(());
+    }
+    match pattern {
+        Value::Id(id) => {
+            // Use the same name for the variable in JavaScript.
+            let name = id.clone();
+            setters.push(format!("const {} = {};", name, value));
+            *ctx = ctx.add(VarId::Var(id.clone()), name);
+        }
+        Value::List(items) => {
+            let value = &cmp_nested(ctx, value, setters);
+            setters.push(format!(
+                "if (!({} && {} === {})) continue;",
+                backend.is_list(value),
+                backend.list_size(value),
+                items.len(),
+            ));
+            for (i, item) in items.iter().enumerate() {
+                cmp_pattern(ctx, &backend.list_get(value, i), item, setters)?;
+            }
+        }
+        Value::Record(fields) => {
+            let value = &cmp_nested(ctx, value, setters);
+            let mut checks = vec![backend.is_record(value)];
+            checks.extend(fields.keys().map(|key| backend.has_field(value, key)));
+            setters.push(format!("if (!({})) continue;", checks.join(" && ")));
+            for (key, field) in fields {
+                cmp_pattern(ctx, &backend.field(value, key), field, setters)?;
+            }
+        }
+        Value::Literal(_)
+        | Value::Expr(_)
+        | Value::Aggregate(_)
+        | Value::Lattice(_)
+        | Value::Binary(..)
+        | Value::Unary(..)
+        | Value::Call(..) => {
+            unreachable!("literal and expression values are always bound")
+        }
+    }
+    Ok(())
+}
+
+/// Bind a value that is destructured by a list or record pattern.
+///
+/// Imported tuples keep their nested values as plain JavaScript, so in the
+/// immutable backend they are converted before being matched.
+fn cmp_nested(ctx: &mut Context, value: &str, setters: &mut Vec<String>) -> String {
+    match ctx.backend {
+        Backend::Immutable => {
+            let name = ctx.gensym("nested");
+            setters.push(format!(
+                "const {} = {};",
+                name,
+                ctx.backend.value_from_js(value)
+            ));
+            name
+        }
+        Backend::Native => value.into(),
     }
 }
 
@@ -659,55 +1730,283 @@ fn cmp_value(ctx: &Context, value: &Value) -> Result<String> {
     Ok(match value {
         Value::Id(id) => ctx.get(&VarId::Var(id.clone()))?,
         Value::Literal(Literal::Number(n)) => n.clone(),
-        Value::Literal(Literal::String(s)) => format!("\"{}\"", s),
+        Value::Literal(Literal::String(s)) => cmp_string(s),
         Value::Literal(Literal::Boolean(b)) => b.to_string(),
+        Value::Literal(Literal::Null) => "null".into(),
         Value::Expr(e) => format!("({})", e),
+        Value::List(items) => {
+            let items = items
+                .iter()
+                .map(|item| cmp_value(ctx, item))
+                .collect::<Result<Vec<_>>>()?;
+            ctx.backend.list(&items.join(", "))
+        }
+        Value::Record(fields) => ctx.backend.record(&cmp_fields(ctx, fields)?),
         Value::Aggregate(aggregate) => cmp_aggregate(ctx, aggregate)?,
+        // Lattice values are combined with existing tuples in `cmp_lattice_insert`.
+        Value::Lattice(lattice) => cmp_value(ctx, &lattice.value)?,
+        Value::Binary(op, lhs, rhs) => cmp_binary(ctx, *op, lhs, rhs)?,
+        Value::Unary(op, value) => {
+            let op = match op {
+                UnaryOp::Neg => "-",
+                UnaryOp::Plus => "+",
+                UnaryOp::Not => "!",
+            };
+            cmp_null_guard(ctx, &[value], "null", |operands| {
+                format!("({}{})", op, operands[0])
+            })?
+        }
+        Value::Call(name, args) => cmp_call(ctx, name, args)?,
     })
 }
 
-fn cmp_aggregate(ctx: &Context, aggregate: &Aggregate) -> Result<String> {
-    if !OPERATORS.contains(&&aggregate.operator[..]) {
-        return Err(Error::UnknownAggregate(aggregate.operator.clone()));
+/// Compile a binary operator with SQL-style null semantics, where a null
+/// operand makes comparisons false and arithmetic null.
+fn cmp_binary(ctx: &Context, op: BinaryOp, lhs: &Value, rhs: &Value) -> Result<String> {
+    let backend = ctx.backend;
+    let fallback = match op {
+        BinaryOp::And | BinaryOp::Or => {
+            // Null is falsy, so it already makes conditions false.
+            let lhs = cmp_value(ctx, lhs)?;
+            let rhs = cmp_value(ctx, rhs)?;
+            return Ok(format!("({} {} {})", lhs, cmp_binary_op(op), rhs));
+        }
+        BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+            "false"
+        }
+        BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem | BinaryOp::Add | BinaryOp::Sub => "null",
+    };
+    cmp_null_guard(ctx, &[lhs, rhs], fallback, |operands| match op {
+        // Lists and records are compared by value, not by reference.
+        BinaryOp::Eq => format!("({})", backend.equals(&operands[0], &operands[1])),
+        BinaryOp::Ne => format!("!({})", backend.equals(&operands[0], &operands[1])),
+        _ => format!("({} {} {})", operands[0], cmp_binary_op(op), operands[1]),
+    })
+}
+
+/// Compile an operation that evaluates to `fallback` if any operand is null.
+///
+/// Operands that are variables or literals are checked in place, while other
+/// operands are passed to a function so that they are only evaluated once.
+/// Operands that are never null, like lists and records, are not checked.
+fn cmp_null_guard(
+    ctx: &Context,
+    values: &[&Value],
+    fallback: &str,
+    operation: impl Fn(&[String]) -> String,
+) -> Result<String> {
+    let operands = values
+        .iter()
+        .map(|value| cmp_value(ctx, value))
+        .collect::<Result<Vec<_>>>()?;
+    let nullable = |value: &Value| match value {
+        Value::Literal(literal) => *literal == Literal::Null,
+        Value::List(_) | Value::Record(_) => false,
+        Value::Binary(op, ..) => !matches!(
+            op,
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge
+        ),
+        _ => true,
+    };
+    if !values.iter().any(|value| nullable(value)) {
+        return Ok(operation(&operands));
+    }
+
+    if values
+        .iter()
+        .all(|value| matches!(value, Value::Id(_) | Value::Literal(_)))
+    {
+        let checks = values
+            .iter()
+            .zip(&operands)
+            .filter(|(value, _)| nullable(value))
+            .map(|(_, operand)| format!("{} === null", operand))
+            .collect::<Vec<_>>();
+        return Ok(format!(
+            "({} ? {} : {})",
+            checks.join(" || "),
+            fallback,
+            operation(&operands),
+        ));
     }
+
+    let params: Vec<String> = match values.len() {
+        n if n <= 2 => [VAR_LHS, VAR_RHS][..n]
+            .iter()
+            .map(|&name| name.into())
+            .collect(),
+        n => (0..n).map(|i| format!("{}{}", VAR_ARG, i)).collect(),
+    };
+    let checks = params
+        .iter()
+        .map(|param| format!("{} === null", param))
+        .collect::<Vec<_>>();
+    Ok(format!(
+        "(({params}) => {checks} ? {fallback} : {body})({operands})",
+        params = params.join(", "),
+        checks = checks.join(" || "),
+        fallback = fallback,
+        body = operation(&params),
+        operands = operands.join(", "),
+    ))
+}
+
+fn cmp_binary_op(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Rem => "%",
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Eq => "===",
+        BinaryOp::Ne => "!==",
+        BinaryOp::Lt => "<",
+        BinaryOp::Le => "<=",
+        BinaryOp::Gt => ">",
+        BinaryOp::Ge => ">=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||",
+    }
+}
+
+fn cmp_call(ctx: &Context, name: &str, args: &[Value]) -> Result<String> {
+    let function = runtime::function(name).ok_or_else(|| Error::UnknownFunction(name.into()))?;
+    if function.args != args.len() {
+        return Err(Error::FunctionArity(name.into(), function.args, args.len()));
+    }
+    // Like operators, calls evaluate to null if any argument is null.
+    let args: Vec<_> = args.iter().collect();
+    let call = cmp_null_guard(ctx, &args, "null", |args| {
+        format!("{}.{}({})", VAR_FUNCTIONS, name, args.join(", "))
+    })?;
+    Ok(ctx.backend.value_from_js(&call))
+}
+
+fn cmp_aggregate(ctx: &Context, aggregate: &Aggregate) -> Result<String> {
+    let ops = [(
+        &aggregate.operator[..],
+        &*aggregate.value,
+        &aggregate.args[..],
+    )];
+    let (subquery_loop, results) = cmp_aggregate_loop(ctx, &ops, &aggregate.subquery)?;
+    Ok(format!(
+        "(() => {{\n{}\nreturn {};\n}})()",
+        subquery_loop, results[0],
+    ))
+}
+
+fn cmp_multi_aggregate(ctx: &Context, multi: &MultiAggregate) -> Result<String> {
+    let ops: Vec<_> = multi
+        .outputs
+        .iter()
+        .map(|output| (&output.operator[..], &output.value, &output.args[..]))
+        .collect();
+    let (subquery_loop, results) = cmp_aggregate_loop(ctx, &ops, &multi.subquery)?;
+    Ok(format!(
+        "(() => {{\n{}\nreturn [{}];\n}})()",
+        subquery_loop,
+        results.join(", "),
+    ))
+}
+
+/// Compile loops over an aggregate subquery, collecting results for each
+/// `(operator, value, args)` triple. There is one loop for each branch of the
+/// disjunctions in the subquery. Returns the statements of the loops, along
+/// with an expression for the final value of each aggregate.
+fn cmp_aggregate_loop(
+    ctx: &Context,
+    ops: &[(&str, &Value, &[Value])],
+    subquery: &[Clause],
+) -> Result<(String, Vec<String>)> {
+    let outer = ctx;
     let mut ctx = ctx.clone(); // Create a new context for this aggregate.
-    let results_var = ctx.gensym("results");
 
-    let subquery_loop = {
+    let mut decls = Vec::new();
+    let mut results_vars = Vec::new();
+    let mut results = Vec::new();
+    for &(operator, _, args) in ops {
+        let agg = match runtime::aggregate(operator) {
+            Some(op) if op.args != args.len() => {
+                return Err(Error::AggregateArity(op.name.into(), op.args, args.len()));
+            }
+            Some(_) => VAR_AGGREGATES,
+            None if ctx.aggregates.contains_key(operator) => VAR_CUSTOM_AGGREGATES,
+            None => return Err(Error::UnknownAggregate(operator.into())),
+        };
+        let args = args
+            .iter()
+            .map(|arg| Ok(format!(", {}", cmp_value(outer, arg)?)))
+            .collect::<Result<String>>()?;
+
+        let results_var = ctx.gensym("results");
+        decls.push(format!("const {} = [];", results_var));
+        results.push(
+            ctx.backend
+                .value_from_js(&format!("{}.{}({}{})", agg, operator, results_var, args)),
+        );
+        results_vars.push(results_var);
+    }
+
+    let branches = expand_clauses(subquery);
+    let seen = if branches.len() > 1 {
+        // Bindings matched by several branches of a disjunction are only
+        // aggregated once, like in the rules that a disjunction expands into.
+        let seen = ctx.gensym("seen");
+        decls.push(format!("const {} = {};", seen, ctx.backend.new_set()));
+        Some(seen)
+    } else {
+        None
+    };
+
+    let mut code = decls.join("\n");
+    for branch in branches {
+        let mut ctx = ctx.clone();
         let mut clauses = Vec::new();
-        for clause in &aggregate.subquery {
+        for clause in &branch {
             clauses.push(cmp_clause(&mut ctx, clause, false, true)?);
         }
 
-        let goal = format!(
-            "{results}.push({value});",
-            results = results_var,
-            value = cmp_value(&ctx, &aggregate.value)?,
-        );
+        // Variables bound inside the subquery, used by operators that return rows.
+        let row_vars: Vec<_> = ctx
+            .map
+            .keys()
+            .filter(|key| !outer.map.contains_key(key))
+            .filter_map(|key| match key {
+                VarId::Var(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let mut goals = Vec::new();
+        for (&(operator, value, _), results_var) in ops.iter().zip(&results_vars) {
+            let mut value = cmp_value(&ctx, value)?;
+            if matches!(runtime::aggregate(operator), Some(op) if op.row) {
+                // Pair each value with the variables bound inside the subquery.
+                let row = cmp_object(&row_vars, |name| ctx.get(&VarId::Var(name.clone())))?;
+                value = format!("[{}, {}]", value, row);
+            }
+            goals.push(format!("{}.push({});", results_var, value));
+        }
 
-        let mut code = String::new();
+        let mut goals = goals.join("\n");
+        if let Some(seen) = &seen {
+            let row = cmp_object(&row_vars, |name| ctx.get(&VarId::Var(name.clone())))?;
+            goals = format!(
+                "if ({}) {{\n{}\n}}",
+                ctx.backend.insert_new(seen, &row),
+                goals,
+            );
+        }
+
+        code += "\n";
         for clause in &clauses {
             code += clause;
             code += "\n";
         }
-        code += &goal;
+        code += &goals;
         code += &"\n}".repeat(clauses.len());
-        code
-    };
-
-    let code = format!(
-        "{agg}.{op}((() => {{
-    const {results} = [];
-    {subquery_loop}
-    return {results};
-}})())",
-        agg = VAR_AGGREGATES,
-        op = aggregate.operator,
-        results = results_var,
-        subquery_loop = subquery_loop,
-    );
-
-    Ok(code)
+    }
+    Ok((code, results))
 }
 
 fn cmp_set_update_to_new(ctx: &Context) -> Result<String> {
@@ -716,35 +2015,539 @@ fn cmp_set_update_to_new(ctx: &Context) -> Result<String> {
         .iter()
         .map(|name| {
             Ok(format!(
-                "{} = {}.asImmutable();",
+                "{} = {};",
                 ctx.get(&VarId::Update(name.clone()))?,
-                ctx.get(&VarId::New(name.clone()))?,
+                ctx.backend.freeze(&ctx.get(&VarId::New(name.clone()))?),
             ))
         })
         .collect::<Result<Box<_>>>()?;
     Ok(setters.join("\n"))
 }
 
-fn cmp_output(ctx: &Context) -> Result<String> {
-    let outputs: BTreeSet<String> = ctx
-        .results
-        .iter()
-        .chain(ctx.imports.iter())
-        .cloned()
-        .collect();
-    let obj = cmp_object(&outputs, |name| {
-        Ok(format!("{}.toJS()", ctx.get(&VarId::Set(name.clone()))?))
+fn cmp_output(ctx: &Context, prog: &Program) -> Result<String> {
+    // Only relations visible to other cells are returned.
+    let obj = cmp_object(&prog.results(), |name| {
+        if ctx.lattices.contains_key(name) {
+            // Only output the best tuple for each key of a lattice relation.
+            return Ok(ctx.backend.map_to_js(&ctx.get(&VarId::Best(name.clone()))?));
+        }
+        Ok(ctx.backend.to_js(&ctx.get(&VarId::Set(name.clone()))?))
     })?;
     Ok(format!("return {};", obj))
 }
 
+/// Return a JavaScript object key for a name, quoting it if needed.
+fn cmp_key(name: &str) -> String {
+    if !is_identifier(name) {
+        cmp_string(name)
+    } else {
+        name.into()
+    }
+}
+
+/// Access a property of a JavaScript object by name, quoting it if needed.
+fn cmp_member(obj: &str, name: &str) -> String {
+    if !is_identifier(name) {
+        format!("{}[{}]", obj, cmp_string(name))
+    } else {
+        format!("{}.{}", obj, name)
+    }
+}
+
+/// Returns whether a name can be written as a JavaScript identifier. Relations
+/// from modules and templates have names like `graph.path` or `closure!1_step`
+/// that cannot.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+/// Quote a string as a JavaScript string literal, escaping special characters.
+fn cmp_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            // Line and paragraph separators are not allowed in string literals
+            // before ES2019, so they are escaped along with control characters.
+            '\u{2028}' | '\u{2029}' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 fn cmp_object<T: Copy + Display, U: Display>(
     fields: impl IntoIterator<Item = T>,
     value_fn: impl Fn(T) -> Result<U>,
 ) -> Result<String> {
     let fields = fields
         .into_iter()
-        .map(|field| value_fn(field).map(|value| format!("{}: {}", field, value)))
+        .map(|field| {
+            value_fn(field).map(|value| format!("{}: {}", cmp_key(&field.to_string()), value))
+        })
         .collect::<Result<Box<_>>>()?;
     Ok(format!("{{{}}}", fields.join(", ")))
 }
+
+impl Backend {
+    /// Helper definitions that must precede the generated code.
+    fn prelude(self) -> String {
+        match self {
+            Backend::Immutable => "".into(),
+            Backend::Native => format!(
+                "
+const {canon} = (k, v) => typeof v === \"number\" && !Number.isFinite(v)
+    ? {{ \"\\u0000number\": String(v) }}
+    : v !== null && typeof v === \"object\" && !Array.isArray(v)
+    ? Object.fromEntries(Object.entries(v).sort(([a], [b]) => a < b ? -1 : a > b ? 1 : 0))
+    : v;
+const {key} = ({obj}, skip) => JSON.stringify(Object.keys({obj}).filter(k => k !== skip).sort().map(k => [k, {obj}[k]]), {canon});
+const {push} = (map, key, {obj}) => {{
+    const value = map.get(key);
+    if (value === undefined) map.set(key, [{obj}]);
+    else value.push({obj});
+}};",
+                key = VAR_KEY,
+                push = VAR_PUSH,
+                obj = VAR_OBJ,
+                canon = VAR_CANON,
+            )
+            .trim()
+            .into(),
+        }
+    }
+
+    /// An empty relation.
+    fn empty_set(self) -> String {
+        match self {
+            Backend::Immutable => format!("{}.Set()", VAR_IMMUTABLE),
+            Backend::Native => "new Map()".into(),
+        }
+    }
+
+    /// An empty index, mapping bound fields to lists of tuples.
+    fn empty_index(self) -> String {
+        match self {
+            Backend::Immutable => format!("{}.Map()", VAR_IMMUTABLE),
+            Backend::Native => "new Map()".into(),
+        }
+    }
+
+    /// An empty relation that will be added to during an iteration.
+    fn new_set(self) -> String {
+        match self {
+            Backend::Immutable => format!("{}.Set().asMutable()", VAR_IMMUTABLE),
+            Backend::Native => "new Map()".into(),
+        }
+    }
+
+    /// Statement filling a relation with plain objects from an array.
+    fn init_set(self, v: &str, source: &str) -> String {
+        let code = match self {
+            Backend::Immutable => format!(
+                "
+{v} = {v}.withMutations({v} => {{
+    for (const {obj} of {source}) {{
+        {v}.add({imm}.Map({obj}));
+    }}
+}});
+",
+                v = v,
+                obj = VAR_OBJ,
+                source = source,
+                imm = VAR_IMMUTABLE,
+            ),
+            Backend::Native => format!(
+                "
+for (const {obj} of {source}) {{
+    {v}.set({key}({obj}), {obj});
+}}
+",
+                v = v,
+                obj = VAR_OBJ,
+                source = source,
+                key = VAR_KEY,
+            ),
+        };
+        code.trim().into()
+    }
+
+    /// Statement filling an index with plain objects from an array.
+    fn init_index(self, v: &str, source: &str, bound: &BTreeSet<String>) -> Result<String> {
+        let key = self.index_key(bound, |field| {
+            Ok(format!("({}.{} ?? null)", VAR_OBJ, field))
+        })?;
+        let code = match self {
+            Backend::Immutable => format!(
+                "
+{v} = {v}.withMutations({v} => {{
+    for (const {obj} of {source}) {{
+        {v}.update({key}, value => {{
+            if (value === undefined) value = [];
+            value.push({imm}.Map({obj}));
+            return value;
+        }});
+    }}
+}});",
+                v = v,
+                obj = VAR_OBJ,
+                source = source,
+                imm = VAR_IMMUTABLE,
+                key = key,
+            ),
+            Backend::Native => format!(
+                "
+for (const {obj} of {source}) {{
+    {push}({v}, {key}, {obj});
+}}",
+                v = v,
+                obj = VAR_OBJ,
+                source = source,
+                push = VAR_PUSH,
+                key = key,
+            ),
+        };
+        Ok(code.trim().into())
+    }
+
+    /// Statement merging new tuples from an update into a relation.
+    fn merge(self, set: &str, update: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{v} = {v}.merge({upd});", v = set, upd = update),
+            Backend::Native => format!(
+                "for (const [key, {obj}] of {upd}) {v}.set(key, {obj});",
+                v = set,
+                upd = update,
+                obj = VAR_OBJ,
+            ),
+        }
+    }
+
+    /// Statement adding new tuples from an update to an index, while also
+    /// declaring an index over only the updated tuples.
+    fn update_index(
+        self,
+        index: &str,
+        index_update: &str,
+        update: &str,
+        bound: &BTreeSet<String>,
+    ) -> Result<String> {
+        let key = self.index_key(bound, |field| Ok(self.field(VAR_OBJ, field)))?;
+        let code = match self {
+            Backend::Immutable => format!(
+                "
+{v} = {v}.asMutable();
+let {ind_upd} = {imm}.Map().asMutable();
+for (const {obj} of {upd}) {{
+    const key = {key};
+    {v}.update(key, value => {{
+        if (value === undefined) value = [];
+        value.push({obj});
+        return value;
+    }});
+    {ind_upd}.update(key, value => {{
+        if (value === undefined) value = [];
+        value.push({obj});
+        return value;
+    }});
+}}
+{v} = {v}.asImmutable();
+{ind_upd} = {ind_upd}.asImmutable();
+",
+                imm = VAR_IMMUTABLE,
+                obj = VAR_OBJ,
+                v = index,
+                upd = update,
+                ind_upd = index_update,
+                key = key,
+            ),
+            Backend::Native => format!(
+                "
+const {ind_upd} = new Map();
+for (const {obj} of {upd}.values()) {{
+    const key = {key};
+    {push}({v}, key, {obj});
+    {push}({ind_upd}, key, {obj});
+}}
+",
+                obj = VAR_OBJ,
+                v = index,
+                upd = update,
+                ind_upd = index_update,
+                push = VAR_PUSH,
+                key = key,
+            ),
+        };
+        Ok(code.trim().into())
+    }
+
+    /// Statements adding a goal object to the new tuples, if not yet present.
+    fn insert(self, set: &str, new: &str, goal_obj: &str) -> String {
+        let code = match self {
+            Backend::Immutable => format!(
+                "
+const {goal} = {imm}.Map({goal_obj});
+if (!{set}.includes({goal})) {new}.add({goal});
+",
+                goal = VAR_GOAL,
+                imm = VAR_IMMUTABLE,
+                goal_obj = goal_obj,
+                set = set,
+                new = new,
+            ),
+            Backend::Native => format!(
+                "
+const {goal} = {goal_obj};
+const {goal_key} = {key}({goal});
+if (!{set}.has({goal_key})) {new}.set({goal_key}, {goal});
+",
+                goal = VAR_GOAL,
+                goal_key = VAR_GOAL_KEY,
+                key = VAR_KEY,
+                goal_obj = goal_obj,
+                set = set,
+                new = new,
+            ),
+        };
+        code.trim().into()
+    }
+
+    /// Expression for the finished tuples added in an iteration.
+    fn freeze(self, new: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.asImmutable()", new),
+            Backend::Native => new.into(),
+        }
+    }
+
+    /// Expression iterating over the tuples of a relation.
+    fn iter(self, set: &str) -> String {
+        match self {
+            Backend::Immutable => set.into(),
+            Backend::Native => format!("{}.values()", set),
+        }
+    }
+
+    /// Expression accessing a field of a tuple, which is null if missing.
+    fn field(self, obj: &str, field: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.get('{}', null)", obj, field),
+            Backend::Native => format!("({}.{} ?? null)", obj, field),
+        }
+    }
+
+    /// Expression for the key of an index, given values for its bound fields.
+    fn index_key<T: Copy + Display>(
+        self,
+        fields: impl IntoIterator<Item = T>,
+        value_fn: impl Fn(T) -> Result<String>,
+    ) -> Result<String> {
+        match self {
+            Backend::Immutable => Ok(format!(
+                "{}.Map({})",
+                VAR_IMMUTABLE,
+                cmp_object(fields, value_fn)?,
+            )),
+            Backend::Native => {
+                let values = fields
+                    .into_iter()
+                    .map(value_fn)
+                    .collect::<Result<Box<_>>>()?;
+                Ok(format!(
+                    "JSON.stringify([{}], {})",
+                    values.join(", "),
+                    VAR_CANON,
+                ))
+            }
+        }
+    }
+
+    /// Expression for a list value, given its comma-separated items.
+    fn list(self, items: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.List([{}])", VAR_IMMUTABLE, items),
+            Backend::Native => format!("Object.freeze([{}])", items),
+        }
+    }
+
+    /// Expression for a record value, given its fields as a JavaScript object.
+    fn record(self, obj: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.Map({})", VAR_IMMUTABLE, obj),
+            Backend::Native => format!("Object.freeze({})", obj),
+        }
+    }
+
+    /// Expression checking whether a value is a list.
+    fn is_list(self, value: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.List.isList({})", VAR_IMMUTABLE, value),
+            Backend::Native => format!("Array.isArray({})", value),
+        }
+    }
+
+    /// Expression for the number of items in a list.
+    fn list_size(self, list: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.size", list),
+            Backend::Native => format!("{}.length", list),
+        }
+    }
+
+    /// Expression accessing an item of a list.
+    fn list_get(self, list: &str, index: usize) -> String {
+        match self {
+            Backend::Immutable => format!("{}.get({})", list, index),
+            Backend::Native => format!("{}[{}]", list, index),
+        }
+    }
+
+    /// Expression checking whether a value is a record.
+    fn is_record(self, value: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.Map.isMap({})", VAR_IMMUTABLE, value),
+            Backend::Native => format!(
+                "({v} !== null && typeof {v} === \"object\" && !Array.isArray({v}))",
+                v = value,
+            ),
+        }
+    }
+
+    /// Expression checking whether a record has a field.
+    fn has_field(self, record: &str, field: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.has('{}')", record, field),
+            Backend::Native => format!("Object.hasOwn({}, '{}')", record, field),
+        }
+    }
+
+    /// Expression checking whether two values are equal, comparing lists and
+    /// records by their contents.
+    fn equals(self, a: &str, b: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.is({}, {})", VAR_IMMUTABLE, a, b),
+            Backend::Native => format!(
+                "JSON.stringify({a}, {canon}) === JSON.stringify({b}, {canon})",
+                a = a,
+                b = b,
+                canon = VAR_CANON,
+            ),
+        }
+    }
+
+    /// An empty map that is modified in place.
+    fn mutable_map(self) -> String {
+        match self {
+            Backend::Immutable => format!("{}.Map().asMutable()", VAR_IMMUTABLE),
+            Backend::Native => "new Map()".into(),
+        }
+    }
+
+    /// Expression for a tuple with the given fields, as a JavaScript object.
+    fn tuple(self, obj: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.Map({})", VAR_IMMUTABLE, obj),
+            Backend::Native => obj.into(),
+        }
+    }
+
+    /// Statement adding a tuple to the new tuples, without checking if it is
+    /// already present.
+    fn add_new(self, new: &str, tuple: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.add({});", new, tuple),
+            Backend::Native => format!("{}.set({}({}), {});", new, VAR_KEY, tuple, tuple),
+        }
+    }
+
+    /// Expression adding a tuple to a set that is modified in place, which is
+    /// true if the tuple was not already present.
+    fn insert_new(self, set: &str, obj: &str) -> String {
+        match self {
+            Backend::Immutable => format!(
+                "{set}.size < {set}.add({imm}.Map({obj})).size",
+                set = set,
+                imm = VAR_IMMUTABLE,
+                obj = obj,
+            ),
+            Backend::Native => format!(
+                "{set}.size < {set}.set({key}({obj}), true).size",
+                set = set,
+                key = VAR_KEY,
+                obj = obj,
+            ),
+        }
+    }
+
+    /// Expression for a copy of a tuple with one field replaced.
+    fn with_field(self, obj: &str, field: &str, value: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.set('{}', {})", obj, field, value),
+            Backend::Native => format!("{{...{}, {}: {}}}", obj, field, value),
+        }
+    }
+
+    /// Expression for the key of a tuple in a lattice relation, which consists
+    /// of every field other than the lattice field.
+    fn lattice_key(self, obj: &str, field: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.delete('{}')", obj, field),
+            Backend::Native => format!("{}({}, {})", VAR_KEY, obj, cmp_string(field)),
+        }
+    }
+
+    /// Expression for a collection holding a single value.
+    fn singleton(self, value: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.Set([{}])", VAR_IMMUTABLE, value),
+            Backend::Native => format!("[{}]", value),
+        }
+    }
+
+    /// Expression for a collection with a value added to it.
+    fn add(self, collection: &str, value: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.add({})", collection, value),
+            Backend::Native => format!("[...{}, {}]", collection, value),
+        }
+    }
+
+    /// Expression checking whether a collection contains a value.
+    fn contains(self, collection: &str, value: &str) -> String {
+        format!("{}.includes({})", collection, value)
+    }
+
+    /// Expression converting the values of a map of tuples to plain objects.
+    fn map_to_js(self, map: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.valueSeq().toJS()", map),
+            Backend::Native => format!("Array.from({}.values())", map),
+        }
+    }
+
+    /// Expression converting a plain JavaScript value, such as the result of an
+    /// aggregate, into a value that can be stored in a tuple.
+    fn value_from_js(self, value: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.fromJS({})", VAR_IMMUTABLE, value),
+            Backend::Native => value.into(),
+        }
+    }
+
+    /// Expression converting a relation to an array of plain objects.
+    fn to_js(self, set: &str) -> String {
+        match self {
+            Backend::Immutable => format!("{}.toJS()", set),
+            Backend::Native => format!("Array.from({}.values())", set),
+        }
+    }
+}
@@ -3,6 +3,8 @@
 use ariadne::{Color, Fmt, Label, Report, ReportKind, Source};
 use chumsky::prelude::*;
 
+use crate::{codegen, module::ModuleLoader};
+
 /// Format parser errors into a human-readable message.
 pub fn format_errors(src: &str, errors: Vec<Simple<String>>) -> String {
     let mut reports = vec![];
@@ -78,3 +80,50 @@ pub fn format_errors(src: &str, errors: Vec<Simple<String>>) -> String {
 
     reports.join("\n")
 }
+
+/// Format a compile error in a rule that was expanded from a template, pointing
+/// to both the instance and the template's declaration.
+///
+/// Returns `None` for other errors, or if the source of the module containing
+/// the template could not be loaded.
+pub fn format_expansion_error(
+    src: &str,
+    err: &codegen::Error,
+    loader: &dyn ModuleLoader,
+) -> Option<String> {
+    let (err, origin) = match err {
+        codegen::Error::Located(err, origin) => (err, origin),
+        _ => return None,
+    };
+    let expansion = origin.expansion.as_ref()?;
+    let module_src;
+    let (src, message) = match &origin.module {
+        Some(path) => {
+            module_src = loader.load(path).ok()?;
+            (&module_src[..], format!("{} (in module \"{}\")", err, path))
+        }
+        None => (src, err.to_string()),
+    };
+
+    let report = Report::build(ReportKind::Error, (), expansion.instance.start)
+        .with_message(message)
+        .with_label(
+            Label::new(expansion.instance.clone())
+                .with_message(format!(
+                    "In this instance of template {}",
+                    (&expansion.template).fg(Color::Red)
+                ))
+                .with_color(Color::Red),
+        )
+        .with_label(
+            Label::new(expansion.declaration.clone())
+                .with_message(format!(
+                    "Template {} is declared here",
+                    (&expansion.template).fg(Color::Yellow)
+                ))
+                .with_color(Color::Yellow),
+        );
+    let mut buf = vec![];
+    report.finish().write(Source::from(src), &mut buf).unwrap();
+    Some(String::from_utf8(buf).unwrap())
+}
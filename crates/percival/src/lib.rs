@@ -6,4 +6,9 @@
 pub mod ast;
 pub mod codegen;
 pub mod errors;
+pub mod lint;
+pub mod module;
+pub mod notebook;
 pub mod parser;
+pub mod resolve;
+pub mod runtime;
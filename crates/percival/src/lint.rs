@@ -0,0 +1,237 @@
+//! Static analysis that warns about likely mistakes in Percival programs.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::ast::{Clause, Origin, Program, Rule, Value};
+
+/// A potential problem found by static analysis, which does not prevent the
+/// program from being compiled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Warning {
+    /// A recursive rule computes a field of its goal from variables bound by
+    /// recursive clauses, so it may keep inventing new values forever.
+    ValueInvention {
+        /// Name of the relation produced by the rule.
+        relation: String,
+        /// Field of the goal that is computed.
+        field: String,
+        /// Recursively bound variables that the field is computed from.
+        vars: BTreeSet<String>,
+        /// Where the rule was written.
+        origin: Origin,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::ValueInvention {
+                relation,
+                field,
+                vars,
+                origin,
+            } => {
+                write!(
+                    f,
+                    "Recursive rule for \"{}\" computes field \"{}\" from recursive variables \
+                     {:?}, which may never terminate",
+                    relation, field, vars,
+                )?;
+                if !origin.is_main() {
+                    write!(f, " (in {})", origin)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Check a program for potential problems, returning a list of warnings.
+pub fn check(prog: &Program) -> Vec<Warning> {
+    let mut graph: BTreeMap<&str, BTreeSet<String>> = BTreeMap::new();
+    for rule in &prog.rules {
+        graph
+            .entry(&rule.goal.name)
+            .or_default()
+            .extend(rule.deps());
+    }
+
+    let mut warnings = Vec::new();
+    for rule in prog.rules.iter().flat_map(Rule::expand) {
+        check_value_invention(&graph, &rule, &mut warnings);
+    }
+    warnings
+}
+
+/// Returns whether relation `from` transitively depends on relation `to`.
+fn depends_on(graph: &BTreeMap<&str, BTreeSet<String>>, from: &str, to: &str) -> bool {
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![from];
+    while let Some(name) = stack.pop() {
+        if name == to {
+            return true;
+        }
+        if visited.insert(name) {
+            if let Some(deps) = graph.get(name) {
+                stack.extend(deps.iter().map(String::as_str));
+            }
+        }
+    }
+    false
+}
+
+/// Flag goal fields that are computed from recursively bound variables.
+///
+/// Rules with a condition on the origins of a computed goal field are assumed
+/// to be bounded by that condition, so they are not reported. Conditions on
+/// other recursive variables do not stop the computed values from growing.
+fn check_value_invention(
+    graph: &BTreeMap<&str, BTreeSet<String>>,
+    rule: &Rule,
+    warnings: &mut Vec<Warning>,
+) {
+    // Map from each variable to the recursively bound variables it came from.
+    let mut origins: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    // Variables that were computed, rather than copied, from their origins.
+    let mut computed = BTreeSet::new();
+    // Recursively bound variables that are read by conditions.
+    let mut guarded = BTreeSet::new();
+
+    let origins_of = |origins: &BTreeMap<String, BTreeSet<String>>, vars: BTreeSet<String>| {
+        vars.iter()
+            .filter_map(|var| origins.get(var))
+            .flatten()
+            .cloned()
+            .collect::<BTreeSet<_>>()
+    };
+
+    for clause in &rule.clauses {
+        match clause {
+            Clause::Fact(fact) if depends_on(graph, &fact.name, &rule.goal.name) => {
+                for value in fact.props.values() {
+                    for id in value.pattern_vars() {
+                        origins.entry(id.into()).or_default().insert(id.into());
+                    }
+                }
+            }
+            Clause::Fact(_) => (),
+            Clause::Expr(expr) => {
+                guarded.extend(origins_of(&origins, js_identifiers(expr)));
+            }
+            Clause::Condition(value) => {
+                guarded.extend(origins_of(&origins, value_vars(value)));
+            }
+            Clause::Binding(name, value) => {
+                let sources = origins_of(&origins, value_vars(value));
+                if !sources.is_empty() {
+                    if !matches!(value, Value::Id(id) if !computed.contains(id)) {
+                        computed.insert(name.clone());
+                    }
+                    origins.insert(name.clone(), sources);
+                }
+            }
+            Clause::MultiAggregate(multi) => {
+                for output in &multi.outputs {
+                    let vars = output.args.iter().flat_map(value_vars).collect();
+                    let sources = origins_of(&origins, vars);
+                    if !sources.is_empty() {
+                        computed.insert(output.name.clone());
+                        origins.insert(output.name.clone(), sources);
+                    }
+                }
+            }
+            Clause::Disjunction(_) => unreachable!("rules are expanded before checking"),
+        }
+    }
+
+    let mut invented = Vec::new();
+    for (field, value) in &rule.goal.props {
+        let vars = match value {
+            Value::Id(id) if computed.contains(id) => origins[id].clone(),
+            Value::Expr(_)
+            | Value::List(_)
+            | Value::Record(_)
+            | Value::Binary(..)
+            | Value::Unary(..)
+            | Value::Call(..) => origins_of(&origins, value_vars(value)),
+            _ => continue,
+        };
+        if !vars.is_empty() {
+            invented.push((field, vars));
+        }
+    }
+
+    if invented.iter().any(|(_, vars)| !vars.is_disjoint(&guarded)) {
+        return;
+    }
+    for (field, vars) in invented {
+        warnings.push(Warning::ValueInvention {
+            relation: rule.goal.name.clone(),
+            field: field.clone(),
+            vars,
+            origin: rule.origin.clone(),
+        });
+    }
+}
+
+/// Returns the names of all variables that a value may read from.
+pub(crate) fn value_vars(value: &Value) -> BTreeSet<String> {
+    match value {
+        Value::Id(id) => [id.clone()].into_iter().collect(),
+        Value::Expr(expr) => js_identifiers(expr),
+        Value::Aggregate(aggregate) => aggregate.args.iter().flat_map(value_vars).collect(),
+        Value::Lattice(lattice) => value_vars(&lattice.value),
+        Value::List(items) => items.iter().flat_map(value_vars).collect(),
+        Value::Record(fields) => fields.values().flat_map(value_vars).collect(),
+        Value::Binary(_, lhs, rhs) => value_vars(lhs).into_iter().chain(value_vars(rhs)).collect(),
+        Value::Unary(_, value) => value_vars(value),
+        Value::Call(_, args) => args.iter().flat_map(value_vars).collect(),
+        Value::Literal(_) => BTreeSet::new(),
+    }
+}
+
+/// Approximate the set of free identifiers in a raw JavaScript expression.
+///
+/// This skips over string literals and property accesses like `a.b`, which is
+/// good enough for finding the Percival variables that an expression uses.
+pub(crate) fn js_identifiers(expr: &str) -> BTreeSet<String> {
+    let mut idents = BTreeSet::new();
+    let mut chars = expr.chars().peekable();
+    let mut prev = ' ';
+    while let Some(c) = chars.next() {
+        if c == '"' || c == '\'' {
+            while let Some(d) = chars.next() {
+                if d == '\\' {
+                    chars.next();
+                } else if d == c {
+                    break;
+                }
+            }
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let mut ident = c.to_string();
+            while let Some(&d) = chars.peek() {
+                if !(d.is_alphanumeric() || d == '_' || d == '$') {
+                    break;
+                }
+                ident.push(d);
+                chars.next();
+            }
+            if prev != '.' {
+                idents.insert(ident);
+            }
+            prev = 'a';
+            continue;
+        } else if c.is_ascii_digit() {
+            while matches!(chars.peek(), Some(d) if d.is_alphanumeric() || *d == '.') {
+                chars.next();
+            }
+        }
+        if !c.is_whitespace() {
+            prev = c;
+        }
+    }
+    idents
+}
@@ -0,0 +1,236 @@
+//! Linking of modules included with the `use` keyword.
+//!
+//! Each module is loaded by a host-provided [`ModuleLoader`], parsed, and
+//! merged into the program that uses it. Relations and imports defined by a
+//! module are renamed into its namespace, like `graph.path`, and are private
+//! to the program. Any other relations that a module refers to are left as
+//! they are, so a module can compute over relations of the program using it.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use thiserror::Error;
+
+use crate::{
+    ast::{Clause, Fact, Program, Value},
+    errors::format_errors,
+    parser::Grammar,
+};
+
+/// An error produced while linking modules.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The loader failed to read the source of a module.
+    #[error("Could not load module \"{0}\": {1}")]
+    Load(String, String),
+
+    /// The source of a module could not be parsed.
+    #[error("Could not parse module \"{0}\":\n{1}")]
+    Parse(String, String),
+
+    /// A module was used by itself, directly or through other modules.
+    #[error("Module \"{0}\" is used cyclically: {1}")]
+    Cycle(String, String),
+
+    /// Two modules were used with the same namespace.
+    #[error("Namespace \"{0}\" is used by more than one module")]
+    DuplicateNamespace(String),
+
+    /// A qualified relation referred to a namespace with no module.
+    #[error("Namespace \"{0}\" does not refer to any module")]
+    UnknownNamespace(String),
+
+    /// A qualified relation was not visible in its module.
+    #[error("Module \"{0}\" does not provide relation \"{1}\"")]
+    UnknownRelation(String, String),
+
+    /// A rule tried to add to a relation from a module.
+    #[error("Relation \"{0}\" is from a module, and cannot be the goal of a rule")]
+    ModuleGoal(String),
+}
+
+/// Result returned by the linker.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A source of modules, which loads each file's contents by its path.
+pub trait ModuleLoader {
+    /// Return the source code of the module at a path.
+    fn load(&self, path: &str) -> std::result::Result<String, String>;
+}
+
+impl<F: Fn(&str) -> std::result::Result<String, String>> ModuleLoader for F {
+    fn load(&self, path: &str) -> std::result::Result<String, String> {
+        self(path)
+    }
+}
+
+/// Link the modules used by a program, returning a program without any uses.
+pub fn link(prog: &Program, loader: &dyn ModuleLoader) -> Result<Program> {
+    let mut linker = Linker {
+        loader,
+        grammar: Grammar::new(),
+        stack: Vec::new(),
+        loaded: BTreeSet::new(),
+    };
+    linker.link(prog.clone(), "")
+}
+
+struct Linker<'a> {
+    loader: &'a dyn ModuleLoader,
+    grammar: Grammar,
+    /// Paths of the modules currently being linked, for finding cycles.
+    stack: Vec<String>,
+    /// Paths of the modules loaded so far, for declaring their aggregates once.
+    loaded: BTreeSet<String>,
+}
+
+impl Linker<'_> {
+    fn link(&mut self, mut prog: Program, path: &str) -> Result<Program> {
+        // Relations visible in each namespace, by the names used to refer to them.
+        let mut provided: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut modules = Vec::new();
+
+        for module in std::mem::take(&mut prog.uses) {
+            let namespace = module.namespace();
+            if provided.contains_key(&namespace) {
+                return Err(Error::DuplicateNamespace(namespace));
+            }
+            let module_path = join_path(path, &module.path);
+            let linked = self.load(&module_path)?;
+
+            let defined: BTreeSet<_> = linked
+                .relations()
+                .into_iter()
+                .chain(linked.imports())
+                .collect();
+            let rename = |name: &str| {
+                defined
+                    .contains(name)
+                    .then(|| format!("{}.{}", namespace, name))
+            };
+            provided.insert(namespace.clone(), linked.results());
+            modules.push(rename_program(linked, &rename));
+        }
+
+        let check_fact = |fact: &Fact| {
+            if let Some((namespace, name)) = fact.name.split_once('.') {
+                let visible = provided
+                    .get(namespace)
+                    .ok_or_else(|| Error::UnknownNamespace(namespace.into()))?;
+                if !visible.contains(name) {
+                    return Err(Error::UnknownRelation(namespace.into(), name.into()));
+                }
+            }
+            Ok(())
+        };
+        let tests = &prog.tests;
+        for rule in prog
+            .rules
+            .iter()
+            .chain(tests.iter().flat_map(|test| &test.rules))
+        {
+            if rule.goal.name.contains('.') {
+                return Err(Error::ModuleGoal(rule.goal.name.clone()));
+            }
+            let values = rule.goal.props.values().flat_map(Value::facts);
+            for fact in rule.clauses.iter().flat_map(Clause::facts).chain(values) {
+                check_fact(fact)?;
+            }
+        }
+        for assert in prog
+            .asserts
+            .iter()
+            .chain(tests.iter().flat_map(|test| &test.asserts))
+        {
+            for fact in assert.left.facts().into_iter().chain(assert.right.facts()) {
+                check_fact(fact)?;
+            }
+        }
+
+        for module in modules {
+            prog.private.extend(module.relations());
+            prog.private.extend(module.imports());
+            prog.rules.extend(module.rules);
+            prog.imports.extend(module.imports);
+            prog.aggregates.extend(module.aggregates);
+        }
+        Ok(prog)
+    }
+
+    /// Load, parse, and link the module at a path.
+    fn load(&mut self, path: &str) -> Result<Program> {
+        if let Some(start) = self.stack.iter().position(|used| used == path) {
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(path.into());
+            return Err(Error::Cycle(path.into(), cycle.join(" -> ")));
+        }
+        let mut src = self
+            .loader
+            .load(path)
+            .map_err(|err| Error::Load(path.into(), err))?;
+        if !src.ends_with('\n') {
+            src += "\n";
+        }
+        let mut module = self
+            .grammar
+            .parse(&src)
+            .map_err(|errors| Error::Parse(path.into(), format_errors(&src, errors)))?;
+        if !self.loaded.insert(path.into()) {
+            // Aggregates are global, so a module used more than once only
+            // declares them the first time.
+            module.aggregates.clear();
+        }
+
+        self.stack.push(path.into());
+        let mut linked = self.link(module, path)?;
+        self.stack.pop();
+        for rule in &mut linked.rules {
+            // Rules from nested modules already know which file they are from.
+            rule.origin.module.get_or_insert_with(|| path.into());
+        }
+        Ok(linked)
+    }
+}
+
+/// Resolve the path of a module relative to the file that uses it.
+fn join_path(base: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    if !path.starts_with('/') {
+        parts.extend(base.split('/').filter(|part| !part.is_empty()));
+        parts.pop(); // Remove the file name of the base.
+    }
+    let mut leading = 0;
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." if parts.len() > leading => {
+                parts.pop();
+            }
+            ".." => {
+                parts.push("..");
+                leading += 1;
+            }
+            _ => parts.push(part),
+        }
+    }
+    let joined = parts.join("/");
+    if path.starts_with('/') {
+        format!("/{}", joined)
+    } else {
+        joined
+    }
+}
+
+/// Rename the relations and imports of a program that `rename` maps.
+fn rename_program(mut prog: Program, rename: &dyn Fn(&str) -> Option<String>) -> Program {
+    for rule in &mut prog.rules {
+        rule.rename_relations(rename);
+    }
+    for import in &mut prog.imports {
+        if let Some(name) = rename(&import.name) {
+            import.name = name;
+        }
+    }
+    prog.exports.clear();
+    prog.private.clear();
+    prog
+}
@@ -0,0 +1,282 @@
+//! Notebooks of code, Markdown, and plot cells, in the plaintext format that
+//! the web app saves as `.percival` files.
+//!
+//! Each code cell is a separate program. Cells are connected through the
+//! relations that they produce and depend on, so the notebook is evaluated in
+//! topological order of this graph, as long as every dependency is produced
+//! by exactly one cell and there are no cycles between cells.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use thiserror::Error;
+
+use crate::ast::Program;
+
+/// First line of every notebook file.
+const PREAMBLE: &str = "This is a Percival notebook (https://percival.ink/).\n";
+
+/// Prefix of the header of a visible cell.
+const PREFIX_VISIBLE: &str = "╔═╡";
+
+/// Prefix of the header of a cell with its source hidden.
+const PREFIX_HIDDEN: &str = "╔═╣";
+
+/// An error in the dependency graph of a notebook. Cells are numbered from 1.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A plot cell did not name the relation being plotted.
+    #[error("Cell {}: Expected plot cell to start with `name =>` syntax", .0 + 1)]
+    InvalidPlot(usize),
+
+    /// A relation was produced by more than one cell.
+    #[error("Relation \"{0}\" is defined in multiple cells: {}", format_cells(.1, ", "))]
+    DuplicateProducer(String, Vec<usize>),
+
+    /// A cell depended on a relation that no cell produces.
+    #[error("Cell {}: Dependency \"{1}\" was not found in any cell", .0 + 1)]
+    MissingDependency(usize, String),
+
+    /// Cells depended on each other's relations in a cycle.
+    #[error("Cells depend on each other cyclically: {}", format_cells(.0, " -> "))]
+    Cycle(Vec<usize>),
+}
+
+/// Result returned by the notebook dependency graph.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The kind of content in a notebook cell.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CellType {
+    /// Prose written in Markdown.
+    Markdown,
+    /// A Percival program.
+    Code,
+    /// A JavaScript function plotting a relation, like `data => Plot.dot(...)`.
+    Plot,
+}
+
+impl CellType {
+    fn name(self) -> &'static str {
+        match self {
+            CellType::Markdown => "Markdown",
+            CellType::Code => "Code",
+            CellType::Plot => "Plot",
+        }
+    }
+}
+
+/// A single cell of a notebook.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cell {
+    /// Kind of content in the cell.
+    pub cell_type: CellType,
+    /// Whether the source of the cell is hidden in the notebook.
+    pub hidden: bool,
+    /// Source of the cell.
+    pub value: String,
+}
+
+/// Relations that a code or plot cell depends on and produces.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CellDeps {
+    /// Relations that must be produced by other cells.
+    pub deps: BTreeSet<String>,
+    /// Relations that this cell makes visible to other cells.
+    pub results: BTreeSet<String>,
+}
+
+impl CellDeps {
+    /// Returns the dependencies of a code cell with the given program.
+    pub fn program(prog: &Program) -> Self {
+        Self {
+            deps: prog.deps(),
+            results: prog.results(),
+        }
+    }
+
+    /// Returns the dependencies of a plot cell, which plots the relation named
+    /// before its `=>`. An empty cell has no dependencies.
+    pub fn plot(src: &str) -> Option<Self> {
+        let src = src.trim_start();
+        if src.trim_end().is_empty() {
+            return Some(Self::default());
+        }
+        let is_start = |c: char| c.is_ascii_alphabetic() || c == '_' || c == '$';
+        if !src.starts_with(is_start) {
+            return None;
+        }
+        let end = src
+            .find(|c: char| !is_start(c) && !c.is_ascii_digit())
+            .unwrap_or(src.len());
+        if !src[end..].trim_start().starts_with("=>") {
+            return None;
+        }
+        Some(Self {
+            deps: BTreeSet::from([src[..end].to_string()]),
+            results: BTreeSet::new(),
+        })
+    }
+}
+
+/// Returns whether some text is a notebook, rather than a single program.
+pub fn is_notebook(text: &str) -> bool {
+    text.starts_with(PREAMBLE.trim_end()) || !unmarshal(text).is_empty()
+}
+
+/// Marshal a list of cells into the plaintext notebook format.
+pub fn marshal(cells: &[Cell]) -> String {
+    let mut output = vec![PREAMBLE.to_string()];
+    for cell in cells {
+        let prefix = if cell.hidden {
+            PREFIX_HIDDEN
+        } else {
+            PREFIX_VISIBLE
+        };
+        output.push(format!(
+            "{} {}\n{}\n",
+            prefix,
+            cell.cell_type.name(),
+            cell.value,
+        ));
+    }
+    output.join("\n")
+}
+
+/// Unmarshal a plaintext notebook into its list of cells.
+pub fn unmarshal(text: &str) -> Vec<Cell> {
+    let mut cells = Vec::new();
+    let mut current: Option<(CellType, bool, usize)> = None;
+    let mut pos = 0;
+    while let Some(offset) = text[pos..].find('\n') {
+        let newline = pos + offset;
+        let line = newline + 1;
+        match parse_header(&text[line..]) {
+            Some((cell_type, hidden, len)) => {
+                // The line break before a header is not part of the cell.
+                let end = match text[..newline].ends_with('\r') {
+                    true => newline - 1,
+                    false => newline,
+                };
+                if let Some((cell_type, hidden, start)) = current.take() {
+                    cells.push(make_cell(cell_type, hidden, &text[start..end]));
+                }
+                current = Some((cell_type, hidden, line + len));
+                pos = line + len;
+            }
+            None => pos = line,
+        }
+    }
+    if let Some((cell_type, hidden, start)) = current {
+        cells.push(make_cell(cell_type, hidden, &text[start..]));
+    }
+    cells
+}
+
+/// Parse the header line of a cell, returning its type, whether it is hidden,
+/// and the length of the header including its line break.
+fn parse_header(text: &str) -> Option<(CellType, bool, usize)> {
+    for (prefix, hidden) in [(PREFIX_VISIBLE, false), (PREFIX_HIDDEN, true)] {
+        for cell_type in [CellType::Code, CellType::Markdown, CellType::Plot] {
+            let header = format!("{} {}", prefix, cell_type.name());
+            if let Some(rest) = text.strip_prefix(&header) {
+                if rest.starts_with('\n') {
+                    return Some((cell_type, hidden, header.len() + 1));
+                } else if rest.starts_with("\r\n") {
+                    return Some((cell_type, hidden, header.len() + 2));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn make_cell(cell_type: CellType, hidden: bool, value: &str) -> Cell {
+    // Remove the line break that is added after each cell when marshalling.
+    let value = value
+        .strip_suffix("\r\n")
+        .or_else(|| value.strip_suffix('\n'))
+        .unwrap_or(value);
+    Cell {
+        cell_type,
+        hidden,
+        value: value.into(),
+    }
+}
+
+/// Return the order in which to evaluate cells, so that each cell comes after
+/// the cells producing its dependencies. Cells that are not evaluated, such as
+/// Markdown cells, are given as `None` and left out of the order.
+pub fn evaluation_order(cells: &[Option<CellDeps>]) -> Result<Vec<usize>> {
+    let mut producers: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (i, cell) in cells.iter().enumerate() {
+        for relation in cell.iter().flat_map(|cell| &cell.results) {
+            producers.entry(relation).or_default().push(i);
+        }
+    }
+    for (relation, ids) in &producers {
+        if ids.len() > 1 {
+            return Err(Error::DuplicateProducer(relation.to_string(), ids.clone()));
+        }
+    }
+
+    let mut edges = Vec::new();
+    for (i, cell) in cells.iter().enumerate() {
+        let mut deps = BTreeSet::new();
+        for relation in cell.iter().flat_map(|cell| &cell.deps) {
+            match producers.get(&relation[..]) {
+                Some(ids) => deps.insert(ids[0]),
+                None => return Err(Error::MissingDependency(i, relation.clone())),
+            };
+        }
+        edges.push(deps);
+    }
+
+    let mut sorter = Sorter {
+        edges: &edges,
+        visited: vec![false; cells.len()],
+        stack: Vec::new(),
+        order: Vec::new(),
+    };
+    for (i, cell) in cells.iter().enumerate() {
+        if cell.is_some() {
+            sorter.visit(i)?;
+        }
+    }
+    Ok(sorter.order)
+}
+
+/// Depth-first topological sort, which keeps cells in notebook order when
+/// their dependencies allow it.
+struct Sorter<'a> {
+    edges: &'a [BTreeSet<usize>],
+    visited: Vec<bool>,
+    /// Cells currently being visited, for finding cycles.
+    stack: Vec<usize>,
+    order: Vec<usize>,
+}
+
+impl Sorter<'_> {
+    fn visit(&mut self, i: usize) -> Result<()> {
+        if let Some(start) = self.stack.iter().position(|&j| j == i) {
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(i);
+            return Err(Error::Cycle(cycle));
+        }
+        if self.visited[i] {
+            return Ok(());
+        }
+        self.stack.push(i);
+        for &dep in &self.edges[i] {
+            self.visit(dep)?;
+        }
+        self.stack.pop();
+        self.visited[i] = true;
+        self.order.push(i);
+        Ok(())
+    }
+}
+
+fn format_cells(ids: &[usize], separator: &str) -> String {
+    let cells: Vec<_> = ids.iter().map(|i| format!("cell {}", i + 1)).collect();
+    cells.join(separator)
+}
@@ -1,10 +1,17 @@
 //! Parser definitions and error recovery for Percival.
 
-use std::fmt;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
 
 use chumsky::{prelude::*, Stream};
 
-use crate::ast::{Aggregate, Clause, Fact, Import, Literal, Program, Rule, Value};
+use crate::ast::{
+    Aggregate, AggregateOutput, Assert, BinaryOp, Clause, CustomAggregate, Expansion, Fact, Import,
+    ImportFormat, Lattice, Literal, MultiAggregate, Origin, Program, Rule, Test, UnaryOp, Use,
+    Value,
+};
 
 /// A range of character positions in a parser input.
 pub type Span = std::ops::Range<usize>;
@@ -16,7 +23,7 @@ pub enum Token {
     Ident(String),
     /// A numerical constant literal.
     Number(String),
-    /// A string literal, with optional escape sequences.
+    /// A string literal, with escape sequences decoded.
     String(String),
     /// A raw JavaScript expression delimited by backquotes.
     Expr(String),
@@ -29,7 +36,7 @@ impl fmt::Display for Token {
         match self {
             Token::Ident(s) => write!(f, "{}", s),
             Token::Number(n) => write!(f, "{}", n),
-            Token::String(s) => write!(f, "\"{}\"", s),
+            Token::String(s) => write!(f, "{:?}", s),
             Token::Expr(e) => write!(f, "`{}`", e),
             Token::Ctrl(c) => write!(f, "{}", c),
         }
@@ -53,7 +60,8 @@ pub fn lexer() -> BoxedParser<'static, char, Vec<(Token, Span)>, Simple<char>> {
             .map(|c| c.to_string())
             .or_not()
             .map(Option::unwrap_or_default);
-        let integer = sign.chain(digits.clone());
+        // Negative numbers are parsed as negation, so `x-1` is a subtraction.
+        let integer = digits.clone();
 
         let fraction = just('.')
             .chain(digits.clone())
@@ -71,20 +79,35 @@ pub fn lexer() -> BoxedParser<'static, char, Vec<(Token, Span)>, Simple<char>> {
     };
 
     let string = {
-        let normal_char = filter(|&c: &char| c != '"' && c != '\\' && !c.is_control());
+        let normal_char = filter(|&c: &char| c != '"' && c != '\\' && !c.is_control())
+            .map(|c| c.encode_utf16(&mut [0; 2]).to_vec());
         let hex_digit = filter(|&c: &char| c.is_ascii_hexdigit());
-        let control_char = just('\\')
-            .chain(
-                one_of("\"\\/bfnrt")
-                    .map(|c| vec![c])
-                    .or(just('u').chain(hex_digit.repeated().at_least(4).at_most(4))),
-            )
-            .collect::<String>();
+        let escape_char = just('\\').ignore_then(
+            choice((
+                just('"'),
+                just('\\'),
+                just('/'),
+                just('b').to('\x08'),
+                just('f').to('\x0c'),
+                just('n').to('\n'),
+                just('r').to('\r'),
+                just('t').to('\t'),
+            ))
+            .map(|c| c.encode_utf16(&mut [0; 2]).to_vec())
+            .or(just('u')
+                .ignore_then(hex_digit.repeated().exactly(4))
+                .collect::<String>()
+                .map(|hex| vec![u16::from_str_radix(&hex, 16).unwrap()])),
+        );
+        // Escapes are decoded as UTF-16 code units, to allow surrogate pairs.
         let chars = normal_char
-            .map(|c| c.to_string())
-            .or(control_char)
+            .or(escape_char)
             .repeated()
-            .collect();
+            .flatten()
+            .try_map(|units, span| {
+                String::from_utf16(&units)
+                    .map_err(|_| Simple::custom(span, "Invalid unicode escape in string"))
+            });
         just('"').ignore_then(chars).then_ignore(just('"'))
     };
 
@@ -96,6 +119,14 @@ pub fn lexer() -> BoxedParser<'static, char, Vec<(Token, Span)>, Simple<char>> {
 
     let ctrl = choice::<_, Simple<char>>((
         just::<_, _, Simple<char>>(":-"),
+        just::<_, _, Simple<char>>("=="),
+        just::<_, _, Simple<char>>("!="),
+        just::<_, _, Simple<char>>("<="),
+        just::<_, _, Simple<char>>(">="),
+        just::<_, _, Simple<char>>("&&"),
+        just::<_, _, Simple<char>>("||"),
+    ))
+    .or(choice::<_, Simple<char>>((
         just::<_, _, Simple<char>>("("),
         just::<_, _, Simple<char>>(")"),
         just::<_, _, Simple<char>>("["),
@@ -105,8 +136,17 @@ pub fn lexer() -> BoxedParser<'static, char, Vec<(Token, Span)>, Simple<char>> {
         just::<_, _, Simple<char>>(":"),
         just::<_, _, Simple<char>>("."),
         just::<_, _, Simple<char>>(","),
+        just::<_, _, Simple<char>>(";"),
         just::<_, _, Simple<char>>("="),
-    ));
+        just::<_, _, Simple<char>>("<"),
+        just::<_, _, Simple<char>>(">"),
+        just::<_, _, Simple<char>>("+"),
+        just::<_, _, Simple<char>>("-"),
+        just::<_, _, Simple<char>>("*"),
+        just::<_, _, Simple<char>>("/"),
+        just::<_, _, Simple<char>>("%"),
+        just::<_, _, Simple<char>>("!"),
+    )));
 
     let token = choice((
         ident.map(Token::Ident),
@@ -141,15 +181,34 @@ pub fn lexer() -> BoxedParser<'static, char, Vec<(Token, Span)>, Simple<char>> {
 /// If possible, prefer to use the higher-level `Grammar` API directly, rather
 /// than this low-level implementation of a parser combinator.
 pub fn parser() -> BoxedParser<'static, Token, Program, Simple<Token>> {
+    parsers().0
+}
+
+/// A parser combinator from tokens to some output.
+type TokenParser<T> = BoxedParser<'static, Token, T, Simple<Token>>;
+
+/// Construct parser combinators for whole programs and for single values.
+fn parsers() -> (TokenParser<Program>, TokenParser<Value>) {
     use Token::*;
 
-    let ident = select! { Ident(id) => id };
+    // Names starting with two underscores are reserved for generated code, like
+    // the hidden relations and variables of the test runner.
+    let ident = select! { Ident(id) => id }.validate(|id: std::string::String, span, emit| {
+        if id.starts_with("__") {
+            emit(Simple::custom(
+                span,
+                "Identifiers starting with \"__\" are reserved for internal use",
+            ));
+        }
+        id
+    });
 
     let literal = select! {
         Number(n) => Literal::Number(n),
         String(s) => Literal::String(s),
         Ident(b) if b == "true" => Literal::Boolean(true),
         Ident(b) if b == "false" => Literal::Boolean(false),
+        Ident(b) if b == "null" => Literal::Null,
     }
     .labelled("literal");
 
@@ -158,42 +217,129 @@ pub fn parser() -> BoxedParser<'static, Token, Program, Simple<Token>> {
     // Declared here so that we can use it for aggregate subqueries.
     let mut clauses = Recursive::<_, Vec<Clause>, Simple<Token>>::declare();
 
+    // Operator and arguments of an aggregate, such as `percentile[x, 0.9]`.
+    let mut aggregate_op = Recursive::<_, _, Simple<Token>>::declare();
+
     let value = recursive(|value| {
-        let aggregate = ident
-            .then(value.delimited_by(jc("["), jc("]")))
+        aggregate_op.define(
+            ident.then(
+                value
+                    .clone()
+                    .then(jc(",").ignore_then(value.clone()).repeated())
+                    .delimited_by(jc("["), jc("]")),
+            ),
+        );
+
+        let list = value
+            .clone()
+            .separated_by(jc(","))
+            .delimited_by(jc("["), jc("]"));
+
+        let record = ident
+            .then(jc(":").ignore_then(value.clone()).or_not())
+            .try_map(check_prop)
+            .separated_by(jc(","))
+            .delimited_by(jc("{"), jc("}"))
+            .map(|fields| fields.into_iter().collect());
+
+        let aggregate = aggregate_op
+            .clone()
             .then(clauses.clone().delimited_by(jc("{"), jc("}")))
-            .map(|((operator, value), subquery)| Aggregate {
+            .map(|((operator, (value, args)), subquery)| Aggregate {
                 operator,
                 value: Box::new(value),
+                args,
                 subquery,
             });
 
-        choice((
+        let call = ident.then(
+            value
+                .clone()
+                .separated_by(jc(","))
+                .delimited_by(jc("("), jc(")")),
+        );
+
+        let atom = choice((
             aggregate.map(Value::Aggregate),
+            call.map(|(name, args)| Value::Call(name, args)),
+            list.map(Value::List),
+            record.map(Value::Record),
             literal.map(Value::Literal),
-            select! {
-                Expr(e) => Value::Expr(e),
-                Ident(id) => Value::Id(id),
-            },
+            value.clone().delimited_by(jc("("), jc(")")),
+            select! { Expr(e) => Value::Expr(e) },
+            ident.map(Value::Id),
+        ));
+
+        let unary = choice((
+            jc("-").to(UnaryOp::Neg),
+            jc("+").to(UnaryOp::Plus),
+            jc("!").to(UnaryOp::Not),
         ))
-        .labelled("value")
+        .repeated()
+        .then(atom)
+        .foldr(|op, value| match (op, value) {
+            (UnaryOp::Neg, Value::Literal(Literal::Number(n))) if !n.starts_with('-') => {
+                Value::Literal(Literal::Number(format!("-{}", n)))
+            }
+            (UnaryOp::Plus, Value::Literal(Literal::Number(n))) => {
+                Value::Literal(Literal::Number(n))
+            }
+            (op, value) => Value::Unary(op, Box::new(value)),
+        });
+
+        let product = binary(
+            unary,
+            choice((
+                jc("*").to(BinaryOp::Mul),
+                jc("/").to(BinaryOp::Div),
+                jc("%").to(BinaryOp::Rem),
+            )),
+        );
+        let sum = binary(
+            product,
+            jc("+").to(BinaryOp::Add).or(jc("-").to(BinaryOp::Sub)),
+        );
+
+        // Comparisons do not chain, so `a < b < c` is a syntax error.
+        let comparison = sum
+            .clone()
+            .then(
+                choice((
+                    jc("==").to(BinaryOp::Eq),
+                    jc("!=").to(BinaryOp::Ne),
+                    jc("<=").to(BinaryOp::Le),
+                    jc(">=").to(BinaryOp::Ge),
+                    jc("<").to(BinaryOp::Lt),
+                    jc(">").to(BinaryOp::Gt),
+                ))
+                .then(sum)
+                .or_not(),
+            )
+            .map(|(lhs, rhs)| match rhs {
+                Some((op, rhs)) => Value::Binary(op, Box::new(lhs), Box::new(rhs)),
+                None => lhs,
+            });
+
+        let conjunction = binary(comparison, jc("&&").to(BinaryOp::And));
+        binary(conjunction, jc("||").to(BinaryOp::Or)).labelled("value")
     });
 
     let prop = ident
         .then(jc(":").ignore_then(value.clone()).or_not())
-        .try_map(|(id, value), span| {
-            let value = value.unwrap_or_else(|| Value::Id(id.clone()));
-            match &value {
-                Value::Id(name) if is_reserved_word(name) => Err(Simple::custom(
-                    span,
-                    "Cannot use reserved word as a variable binding",
-                )),
-                _ => Ok((id, value)),
-            }
-        })
+        .try_map(check_prop)
         .labelled("prop");
 
-    let fact = ident
+    // Relations from modules are qualified by their namespace, as in
+    // `graph.path(x, y)`.
+    let relation = ident
+        .then(jc(".").ignore_then(ident).repeated())
+        .map(|(name, path)| {
+            path.into_iter()
+                .fold(name, |name, part| format!("{}.{}", name, part))
+        });
+
+    let fact = relation
+        .clone()
         .then(prop.separated_by(jc(",")).delimited_by(jc("("), jc(")")))
         .map(|(name, props)| Fact {
             name,
@@ -201,20 +347,109 @@ pub fn parser() -> BoxedParser<'static, Token, Program, Simple<Token>> {
         })
         .labelled("fact");
 
+    // Goals may also contain lattice operators, which look like aggregates
+    // without a subquery.
+    let goal_value = aggregate_op
+        .clone()
+        .then_ignore(none_of([Ctrl("{")]).rewind())
+        .try_map(|(operator, (value, args)), span| {
+            if args.is_empty() {
+                Ok(Value::Lattice(Lattice {
+                    operator,
+                    value: Box::new(value),
+                }))
+            } else {
+                Err(Simple::custom(
+                    span,
+                    "Lattice operator takes a single value",
+                ))
+            }
+        })
+        .or(value.clone());
+
+    let goal_prop = ident
+        .then(jc(":").ignore_then(goal_value).or_not())
+        .try_map(check_prop)
+        .labelled("prop");
+
+    let goal = relation
+        .clone()
+        .then(
+            goal_prop
+                .separated_by(jc(","))
+                .delimited_by(jc("("), jc(")")),
+        )
+        .try_map(|(name, props), span| {
+            let lattices = props
+                .iter()
+                .filter(|(_, value)| matches!(value, Value::Lattice(_)))
+                .count();
+            if lattices > 1 {
+                return Err(Simple::custom(
+                    span,
+                    "Goal can have at most one lattice field",
+                ));
+            }
+            Ok(Fact {
+                name,
+                props: props.into_iter().collect(),
+            })
+        })
+        .labelled("fact");
+
     let expr = select! { Expr(e) => e };
 
-    let binding = ident.then_ignore(jc("=")).then(value).labelled("binding");
+    // Conditions must have an operator at the top level, to tell them apart
+    // from facts and variables.
+    let condition = value
+        .clone()
+        .try_map(|value, span| match value {
+            Value::Binary(..) | Value::Unary(UnaryOp::Not, _) => Ok(value),
+            _ => Err(Simple::custom(span, "Expected a condition")),
+        })
+        .labelled("condition");
+
+    let binding = ident
+        .then_ignore(jc("="))
+        .then(value.clone())
+        .labelled("binding");
+
+    let multi_aggregate = ident
+        .then_ignore(jc("="))
+        .then(aggregate_op)
+        .map(|(name, (operator, (value, args)))| AggregateOutput {
+            name,
+            operator,
+            value,
+            args,
+        })
+        .separated_by(jc(","))
+        .at_least(1)
+        .delimited_by(jc("["), jc("]"))
+        .then(clauses.clone().delimited_by(jc("{"), jc("}")))
+        .map(|(outputs, subquery)| MultiAggregate { outputs, subquery })
+        .labelled("multi-aggregate");
+
+    let disjunction = clauses
+        .clone()
+        .separated_by(jc(";"))
+        .at_least(1)
+        .delimited_by(jc("("), jc(")"))
+        .labelled("disjunction");
 
     let clause = choice((
+        condition.map(Clause::Condition),
         fact.clone().map(Clause::Fact),
         expr.map(Clause::Expr),
         binding.map(|(name, value)| Clause::Binding(name, value)),
+        multi_aggregate.map(Clause::MultiAggregate),
+        disjunction.map(Clause::Disjunction),
     ))
     .labelled("clause");
 
     clauses.define(clause.clone().separated_by(jc(",")));
 
-    let rule = fact
+    let rule = goal
         .then(
             jc(":-")
                 .ignore_then(clauses)
@@ -228,35 +463,396 @@ pub fn parser() -> BoxedParser<'static, Token, Program, Simple<Token>> {
                 })
                 .or(jc(".").to(Vec::new())),
         )
-        .map(|(goal, clauses)| Rule { goal, clauses })
+        .map(|(goal, clauses)| Rule {
+            goal,
+            clauses,
+            origin: Origin::default(),
+        })
         .labelled("rule");
 
+    let import_format = select! { Ident(k) if k == "as" => () }
+        .ignore_then(ident)
+        .then(
+            ident
+                .then_ignore(jc(":"))
+                .then(value.clone())
+                .separated_by(jc(","))
+                .delimited_by(jc("("), jc(")"))
+                .or_not(),
+        )
+        .map(|(name, options)| ImportFormat {
+            name,
+            options: options.unwrap_or_default().into_iter().collect(),
+        })
+        .labelled("import format");
+
+    let import_sha256 = select! { Ident(k) if k == "sha256" => () }
+        .ignore_then(select! { String(s) => s })
+        .try_map(|hash, span| {
+            if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                Ok(hash.to_ascii_lowercase())
+            } else {
+                Err(Simple::custom(
+                    span,
+                    "Expected a SHA-256 hash of 64 hexadecimal digits",
+                ))
+            }
+        })
+        .labelled("import hash");
+
+    // Imported fields may be renamed from columns that are not identifiers,
+    // such as `mpg: "Miles per Gallon"`.
+    let import_field = ident
+        .then(
+            jc(":")
+                .ignore_then(ident.or(select! { String(s) => s }))
+                .or_not(),
+        )
+        .map(|(field, column)| (field.clone(), column.unwrap_or(field)))
+        .labelled("import field");
+
     let import = select! { Ident(k) if k == "import" => () }
         .ignore_then(ident)
+        .then(
+            import_field
+                .separated_by(jc(","))
+                .at_least(1)
+                .delimited_by(jc("("), jc(")"))
+                .or_not(),
+        )
         .then_ignore(select! { Ident(k) if k == "from" => () })
         .then(select! { String(s) => s })
-        .map(|(name, uri)| Import { name, uri });
+        .then(import_sha256.or_not())
+        .then(import_format.or_not())
+        .map(|((((name, fields), uri), sha256), format)| Import {
+            name,
+            uri,
+            format,
+            sha256,
+            fields: fields.map(|fields| fields.into_iter().collect()),
+        });
+
+    let aggregate = select! { Ident(k) if k == "aggregate" => () }
+        .ignore_then(ident)
+        .then(expr)
+        .map(|(name, reducer)| CustomAggregate { name, reducer });
+
+    let module = select! { Ident(k) if k == "use" => () }
+        .ignore_then(select! { String(s) => s })
+        .then(
+            select! { Ident(k) if k == "as" => () }
+                .ignore_then(ident)
+                .or_not(),
+        )
+        .map(|(path, alias)| Use { path, alias });
+
+    let assert = select! { Ident(k) if k == "assert" => () }
+        .ignore_then(value.clone())
+        .then_ignore(jc("="))
+        .then(value.clone())
+        .then_ignore(jc("."))
+        .map(|(left, right)| Assert { left, right })
+        .labelled("assertion");
+
+    enum TestItem {
+        Rule(Rule),
+        Assert(Assert),
+    }
+
+    let test = select! { Ident(k) if k == "test" => () }
+        .ignore_then(select! { String(s) => s })
+        .then(
+            rule.clone()
+                .map(TestItem::Rule)
+                .or(assert.clone().map(TestItem::Assert))
+                .repeated()
+                .delimited_by(jc("{"), jc("}")),
+        )
+        .map(|(name, items)| {
+            let mut test = Test {
+                name,
+                rules: Vec::new(),
+                asserts: Vec::new(),
+            };
+            for item in items {
+                match item {
+                    TestItem::Rule(rule) => test.rules.push(rule),
+                    TestItem::Assert(assert) => test.asserts.push(assert),
+                }
+            }
+            test
+        })
+        .labelled("test");
+
+    // Templates are rules parameterized by relation names, which are expanded
+    // for each instantiation, such as `closure!(follows, reach).`
+    let template = select! { Ident(k) if k == "template" => () }
+        .ignore_then(ident)
+        .then(
+            ident
+                .separated_by(jc(","))
+                .delimited_by(jc("("), jc(")"))
+                .try_map(|params, span| {
+                    for (i, param) in params.iter().enumerate() {
+                        if params[..i].contains(param) {
+                            return Err(Simple::custom(
+                                span,
+                                format!("Template parameter {} is declared more than once", param),
+                            ));
+                        }
+                    }
+                    Ok(params)
+                }),
+        )
+        .then(rule.clone().repeated().delimited_by(jc("{"), jc("}")))
+        .map_with_span(|((name, params), rules), span| Template {
+            name,
+            params,
+            rules,
+            span,
+        })
+        .labelled("template");
+
+    let instance = ident
+        .then_ignore(jc("!"))
+        .then(
+            relation
+                .separated_by(jc(","))
+                .delimited_by(jc("("), jc(")")),
+        )
+        .then_ignore(jc("."))
+        .map_with_span(|(name, args), span| Instance { name, args, span })
+        .labelled("template instance");
 
     enum Entry {
         Rule(Rule),
         Import(Import),
+        Aggregate(CustomAggregate),
+        Use(Use),
+        Template(Template),
+        Instance(Instance),
+        Assert(Assert),
+        Test(Test),
     }
 
-    let program = choice((rule.map(Entry::Rule), import.map(Entry::Import)))
-        .repeated()
-        .map(|entries| {
-            let mut rules = Vec::new();
-            let mut imports = Vec::new();
-            for entry in entries {
-                match entry {
-                    Entry::Rule(rule) => rules.push(rule),
-                    Entry::Import(import) => imports.push(import),
+    enum Visibility {
+        Export,
+        Private,
+    }
+
+    // Rules and imports may be marked as `export` or `private`, which sets the
+    // visibility of their relation to other cells.
+    let visibility = select! {
+        Ident(k) if k == "export" => Visibility::Export,
+        Ident(k) if k == "private" => Visibility::Private,
+    };
+
+    let entry = choice((
+        rule.map(Entry::Rule),
+        import.map(Entry::Import),
+        instance.map(Entry::Instance),
+    ));
+
+    let program = choice((
+        visibility.map(Some).then(entry.clone()),
+        entry.map(|entry| (None, entry)),
+        aggregate.map(|aggregate| (None, Entry::Aggregate(aggregate))),
+        module.map(|module| (None, Entry::Use(module))),
+        template.map(|template| (None, Entry::Template(template))),
+        assert.map(|assert| (None, Entry::Assert(assert))),
+        test.map(|test| (None, Entry::Test(test))),
+    ))
+    .repeated()
+    .validate(|entries, _, emit| {
+        // Templates may be instantiated before they are declared.
+        let mut templates = BTreeMap::new();
+        // Number of times each template has been instantiated so far.
+        let mut instances = BTreeMap::new();
+        let mut prog = Program::default();
+        let (declared, entries): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|(_, entry)| matches!(entry, Entry::Template(_)));
+        for (_, entry) in declared {
+            if let Entry::Template(template) = entry {
+                if templates.contains_key(&template.name) {
+                    emit(Simple::custom(
+                        template.span,
+                        format!("Template {} is declared more than once", template.name),
+                    ));
+                } else {
+                    templates.insert(template.name.clone(), template);
                 }
             }
-            Program { rules, imports }
-        });
+        }
+
+        for (visibility, entry) in entries {
+            let names: Vec<_> = match entry {
+                Entry::Rule(rule) => {
+                    let name = rule.goal.name.clone();
+                    prog.rules.push(rule);
+                    vec![name]
+                }
+                Entry::Import(import) => {
+                    let name = import.name.clone();
+                    prog.imports.push(import);
+                    vec![name]
+                }
+                Entry::Aggregate(aggregate) => {
+                    prog.aggregates.push(aggregate);
+                    Vec::new()
+                }
+                Entry::Use(module) => {
+                    prog.uses.push(module);
+                    Vec::new()
+                }
+                Entry::Assert(assert) => {
+                    prog.asserts.push(assert);
+                    Vec::new()
+                }
+                Entry::Test(test) => {
+                    prog.tests.push(test);
+                    Vec::new()
+                }
+                Entry::Template(_) => unreachable!("templates are partitioned out"),
+                Entry::Instance(instance) => {
+                    let count = instances.entry(instance.name.clone()).or_insert(0);
+                    *count += 1;
+                    match expand_template(&templates, &instance, *count) {
+                        Ok(rules) => {
+                            let (helpers, names): (BTreeSet<_>, BTreeSet<_>) = rules
+                                .iter()
+                                .map(|rule| rule.goal.name.clone())
+                                .partition(|name| name.contains('!'));
+                            prog.rules.extend(rules);
+                            prog.private.extend(helpers);
+                            names.into_iter().collect()
+                        }
+                        Err(errors) => {
+                            errors.into_iter().for_each(&mut *emit);
+                            Vec::new()
+                        }
+                    }
+                }
+            };
+            for name in names {
+                match visibility {
+                    Some(Visibility::Export) => prog.exports.insert(name),
+                    Some(Visibility::Private) => prog.private.insert(name),
+                    None => false,
+                };
+            }
+        }
+        prog
+    });
+
+    (
+        program.then_ignore(end()).boxed(),
+        value.then_ignore(end()).boxed(),
+    )
+}
+
+/// A template of rules, parameterized by relation names.
+struct Template {
+    name: String,
+    params: Vec<String>,
+    rules: Vec<Rule>,
+    span: Span,
+}
+
+/// An instantiation of a template with relations as arguments.
+struct Instance {
+    name: String,
+    args: Vec<String>,
+    span: Span,
+}
+
+/// Expand an instantiation of a template into rules, renaming each of its
+/// parameters to the corresponding argument.
+///
+/// Other relations defined by the template are helpers, which each instance
+/// gets its own copy of. They are renamed like `closure!2_step` for the second
+/// instance of `closure`, which cannot be written by users.
+fn expand_template(
+    templates: &BTreeMap<String, Template>,
+    instance: &Instance,
+    count: usize,
+) -> Result<Vec<Rule>, Vec<Simple<Token>>> {
+    let template = templates.get(&instance.name).ok_or_else(|| {
+        vec![Simple::custom(
+            instance.span.clone(),
+            format!("Template {} is not declared", instance.name),
+        )]
+    })?;
+    if template.params.len() != instance.args.len() {
+        // Point to the template's declaration as well as the instance.
+        return Err(vec![
+            Simple::custom(
+                instance.span.clone(),
+                format!(
+                    "Template {} takes {} argument(s), but {} were given",
+                    template.name,
+                    template.params.len(),
+                    instance.args.len(),
+                ),
+            ),
+            Simple::custom(
+                template.span.clone(),
+                format!("Template {} is declared here", template.name),
+            ),
+        ]);
+    }
+    let mut args: BTreeMap<_, _> = template
+        .params
+        .iter()
+        .cloned()
+        .zip(instance.args.iter().cloned())
+        .collect();
+    for rule in &template.rules {
+        if !args.contains_key(&rule.goal.name) {
+            let helper = format!("{}!{}_{}", template.name, count, rule.goal.name);
+            args.insert(rule.goal.name.clone(), helper);
+        }
+    }
+    let rename = |name: &str| args.get(name).cloned();
+    let expansion = Expansion {
+        template: template.name.clone(),
+        declaration: template.span.clone(),
+        instance: instance.span.clone(),
+    };
+    let mut rules = template.rules.clone();
+    for rule in &mut rules {
+        rule.rename_relations(&rename);
+        rule.origin.expansion = Some(expansion.clone());
+    }
+    Ok(rules)
+}
+
+/// Fills in the value of a shorthand property, and checks that it does not
+/// bind a reserved word as a variable.
+fn check_prop(
+    (id, value): (String, Option<Value>),
+    span: Span,
+) -> Result<(String, Value), Simple<Token>> {
+    let value = value.unwrap_or_else(|| Value::Id(id.clone()));
+    match &value {
+        Value::Id(name) if is_reserved_word(name) => Err(Simple::custom(
+            span,
+            "Cannot use reserved word as a variable binding",
+        )),
+        _ => Ok((id, value)),
+    }
+}
 
-    program.then_ignore(end()).boxed()
+/// Parse a left-associative chain of binary operations on lower-precedence
+/// values, such as `a + b - c`.
+fn binary(
+    lower: impl Parser<Token, Value, Error = Simple<Token>> + Clone,
+    op: impl Parser<Token, BinaryOp, Error = Simple<Token>> + Clone,
+) -> impl Parser<Token, Value, Error = Simple<Token>> + Clone {
+    lower
+        .clone()
+        .then(op.then(lower).repeated())
+        .foldl(|lhs, (op, rhs)| Value::Binary(op, Box::new(lhs), Box::new(rhs)))
 }
 
 /// Checks if a token is reserved, which cannot be used as an identifier.
@@ -275,8 +871,7 @@ fn is_reserved_word(name: &str) -> bool {
         | "enum" | "await" | "implements" | "package" | "protected" | "interface" | "private"
         | "public" | "null" | "true" | "false" | "let" => true,
 
-        // Internal names, reserved to avoid conflicts
-        _ => name.starts_with("__percival"),
+        _ => false,
     }
 }
 
@@ -284,20 +879,32 @@ fn is_reserved_word(name: &str) -> bool {
 #[derive(Clone)]
 pub struct Grammar {
     lexer: BoxedParser<'static, char, Vec<(Token, Span)>, Simple<char>>,
-    parser: BoxedParser<'static, Token, Program, Simple<Token>>,
+    parser: TokenParser<Program>,
+    value: TokenParser<Value>,
 }
 
 impl Grammar {
     /// Construct a new grammar for the Percival language.
     pub fn new() -> Self {
+        let (parser, value) = parsers();
         Self {
             lexer: lexer(),
-            parser: parser(),
+            parser,
+            value,
         }
     }
 
     /// Parse an input source file, returning the program or a list of errors.
     pub fn parse(&self, src: &str) -> Result<Program, Vec<Simple<String>>> {
+        self.parse_with(&self.parser, src)
+    }
+
+    /// Parse a single value, such as the native expression `x + 1`.
+    pub fn parse_value(&self, src: &str) -> Result<Value, Vec<Simple<String>>> {
+        self.parse_with(&self.value, src)
+    }
+
+    fn parse_with<T>(&self, parser: &TokenParser<T>, src: &str) -> Result<T, Vec<Simple<String>>> {
         let (tokens, errs) = self.lexer.parse_recovery(src);
         let mut errs: Vec<_> = errs.into_iter().map(|e| e.map(|c| c.to_string())).collect();
 
@@ -305,9 +912,9 @@ impl Grammar {
             // println!("Tokens = {:?}", tokens);
             let len = src.chars().count();
             let stream = Stream::from_iter(len..len + 1, tokens.into_iter());
-            let (prog, parse_errs) = self.parser.parse_recovery(stream);
-            match prog {
-                Some(prog) if errs.is_empty() && parse_errs.is_empty() => Ok(prog),
+            let (output, parse_errs) = parser.parse_recovery(stream);
+            match output {
+                Some(output) if errs.is_empty() && parse_errs.is_empty() => Ok(output),
                 _ => {
                     errs.extend(parse_errs.into_iter().map(|e| e.map(|c| c.to_string())));
                     Err(errs)
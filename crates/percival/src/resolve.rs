@@ -0,0 +1,66 @@
+//! Resolution of import URIs into URLs that the runtime can load.
+//!
+//! The `http`, `https`, `gh`, and `npm` schemes are built in. Hosts can handle
+//! other schemes, like `file` or an internal dataset catalog, by registering an
+//! [`ImportResolver`] in the compile options.
+
+use std::{collections::BTreeMap, fmt, rc::Rc};
+
+/// Resolves import URIs with a particular scheme into URLs for the loader.
+pub trait ImportResolver {
+    /// Resolve a full import URI, such as `file://data/cars.json`, into a URL
+    /// that is passed to `__percival.load`, or return an error message.
+    fn resolve(&self, uri: &str) -> Result<String, String>;
+}
+
+impl<F> ImportResolver for F
+where
+    F: Fn(&str) -> Result<String, String>,
+{
+    fn resolve(&self, uri: &str) -> Result<String, String> {
+        self(uri)
+    }
+}
+
+/// A registry of import resolvers, keyed by scheme.
+#[derive(Clone, Default)]
+pub(crate) struct Resolvers(BTreeMap<String, Rc<dyn ImportResolver>>);
+
+impl Resolvers {
+    /// Register a resolver, replacing any existing one for the same scheme.
+    pub(crate) fn insert(&mut self, scheme: String, resolver: Rc<dyn ImportResolver>) {
+        self.0.insert(scheme, resolver);
+    }
+
+    /// Look up the resolver for a scheme, if one was registered.
+    pub(crate) fn get(&self, scheme: &str) -> Option<&dyn ImportResolver> {
+        self.0.get(scheme).map(|resolver| &**resolver)
+    }
+}
+
+impl fmt::Debug for Resolvers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Returns the scheme of a URI, such as `https` or `data`.
+pub fn scheme(uri: &str) -> Option<&str> {
+    let (scheme, _) = uri.split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then_some(scheme)
+}
+
+/// Resolve a URI with one of the built-in schemes, returning `None` if its
+/// scheme is not built in.
+pub fn builtin(uri: &str) -> Option<String> {
+    let (scheme, address) = uri.split_once("://")?;
+    match scheme {
+        "http" | "https" => Some(uri.into()),
+        "gh" => Some(format!("https://cdn.jsdelivr.net/gh/{}", address)),
+        "npm" => Some(format!("https://cdn.jsdelivr.net/npm/{}", address)),
+        _ => None,
+    }
+}
@@ -0,0 +1,393 @@
+//! Built-in JavaScript runtime library for generated Percival code.
+//!
+//! This is the single source of truth for the implementations of built-in
+//! aggregates and functions. Code generation checks operators against these
+//! tables, and the runtime object passed to compiled programs is generated
+//! from them.
+
+/// A built-in aggregate operator, implemented in JavaScript.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Operator {
+    /// Name of the operator, such as `sum`.
+    pub name: &'static str,
+    /// Number of extra arguments, such as the `p` in `percentile[x, p]`.
+    pub args: usize,
+    /// Whether each result is paired with a record of the variables bound in
+    /// the subquery, as `[value, row]`.
+    pub row: bool,
+    /// JavaScript function taking the array of results and any extra arguments.
+    pub js: &'static str,
+}
+
+/// List of built-in aggregate operators.
+pub const AGGREGATES: &[Operator] = &[
+    Operator {
+        name: "count",
+        args: 0,
+        row: false,
+        js: "results => results.length",
+    },
+    Operator {
+        name: "count_distinct",
+        args: 0,
+        row: false,
+        js: r#"results => new Set(results.map(x => typeof x === "object" && x !== null ? JSON.stringify(x) : x)).size"#,
+    },
+    Operator {
+        name: "sum",
+        args: 0,
+        row: false,
+        js: "results => results.reduce((x, y) => x + y, 0)",
+    },
+    Operator {
+        name: "mean",
+        args: 0,
+        row: false,
+        js: "results => results.reduce((x, y) => x + y, 0) / results.length",
+    },
+    Operator {
+        name: "min",
+        args: 0,
+        row: false,
+        js: "results => {
+    let min = null;
+    for (const x of results) {
+        if (min === null || x < min) min = x;
+    }
+    return min;
+}",
+    },
+    Operator {
+        name: "max",
+        args: 0,
+        row: false,
+        js: "results => {
+    let max = null;
+    for (const x of results) {
+        if (max === null || x > max) max = x;
+    }
+    return max;
+}",
+    },
+    Operator {
+        name: "median",
+        args: 0,
+        row: false,
+        js: "results => {
+    if (results.length === 0) return null;
+    const xs = [...results].sort((x, y) => x - y);
+    const mid = xs.length >> 1;
+    return xs.length % 2 ? xs[mid] : (xs[mid - 1] + xs[mid]) / 2;
+}",
+    },
+    Operator {
+        name: "percentile",
+        args: 1,
+        row: false,
+        js: "(results, p) => {
+    if (results.length === 0) return null;
+    const xs = [...results].sort((x, y) => x - y);
+    const pos = Math.min(Math.max(p, 0), 1) * (xs.length - 1);
+    const lo = Math.floor(pos);
+    const hi = Math.ceil(pos);
+    return xs[lo] + (xs[hi] - xs[lo]) * (pos - lo);
+}",
+    },
+    Operator {
+        name: "variance",
+        args: 0,
+        row: false,
+        js: "results => {
+    if (results.length < 2) return null;
+    const mean = results.reduce((x, y) => x + y, 0) / results.length;
+    return results.reduce((s, x) => s + (x - mean) ** 2, 0) / (results.length - 1);
+}",
+    },
+    Operator {
+        name: "stddev",
+        args: 0,
+        row: false,
+        js: "results => {
+    if (results.length < 2) return null;
+    const mean = results.reduce((x, y) => x + y, 0) / results.length;
+    return Math.sqrt(results.reduce((s, x) => s + (x - mean) ** 2, 0) / (results.length - 1));
+}",
+    },
+    Operator {
+        name: "argmin",
+        args: 0,
+        row: true,
+        js: "results => {
+    let best = null;
+    for (const result of results) {
+        if (best === null || result[0] < best[0]) best = result;
+    }
+    return best && best[1];
+}",
+    },
+    Operator {
+        name: "argmax",
+        args: 0,
+        row: true,
+        js: "results => {
+    let best = null;
+    for (const result of results) {
+        if (best === null || result[0] > best[0]) best = result;
+    }
+    return best && best[1];
+}",
+    },
+    Operator {
+        name: "collect",
+        args: 0,
+        row: false,
+        js: "results => [...results]",
+    },
+    Operator {
+        name: "string_agg",
+        args: 1,
+        row: false,
+        js: "(results, separator) => results.join(separator)",
+    },
+    Operator {
+        name: "any",
+        args: 0,
+        row: false,
+        js: "results => results.some(Boolean)",
+    },
+    Operator {
+        name: "all",
+        args: 0,
+        row: false,
+        js: "results => results.every(Boolean)",
+    },
+];
+
+/// Look up a built-in aggregate operator by name.
+pub fn aggregate(name: &str) -> Option<&'static Operator> {
+    AGGREGATES.iter().find(|op| op.name == name)
+}
+
+/// JavaScript object expression containing all built-in aggregates, keyed by
+/// name. This is what compiled code expects as `__percival.aggregates`.
+pub fn aggregates_js() -> String {
+    let fields: Vec<_> = AGGREGATES
+        .iter()
+        .map(|op| format!("{}: ({}),", op.name, op.js))
+        .collect();
+    format!("{{\n{}\n}}", fields.join("\n"))
+}
+
+/// A built-in function that can be called in native expressions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    /// Name of the function, such as `abs`.
+    pub name: &'static str,
+    /// Number of arguments taken by the function.
+    pub args: usize,
+    /// JavaScript function taking the arguments.
+    pub js: &'static str,
+}
+
+/// List of built-in functions.
+///
+/// Every function returns null when any of its arguments is null, like the
+/// operators of native expressions. Dates are represented as milliseconds
+/// since the Unix epoch, and fields of dates are extracted in UTC. Date
+/// functions also accept ISO 8601 strings.
+pub const FUNCTIONS: &[Function] = &[
+    Function {
+        name: "abs",
+        args: 1,
+        js: "x => x === null ? null : Math.abs(x)",
+    },
+    Function {
+        name: "sign",
+        args: 1,
+        js: "x => x === null ? null : Math.sign(x)",
+    },
+    Function {
+        name: "floor",
+        args: 1,
+        js: "x => x === null ? null : Math.floor(x)",
+    },
+    Function {
+        name: "ceil",
+        args: 1,
+        js: "x => x === null ? null : Math.ceil(x)",
+    },
+    Function {
+        name: "round",
+        args: 1,
+        js: "x => x === null ? null : Math.round(x)",
+    },
+    Function {
+        name: "trunc",
+        args: 1,
+        js: "x => x === null ? null : Math.trunc(x)",
+    },
+    Function {
+        name: "sqrt",
+        args: 1,
+        js: "x => x === null ? null : Math.sqrt(x)",
+    },
+    Function {
+        name: "exp",
+        args: 1,
+        js: "x => x === null ? null : Math.exp(x)",
+    },
+    Function {
+        name: "ln",
+        args: 1,
+        js: "x => x === null ? null : Math.log(x)",
+    },
+    Function {
+        name: "log10",
+        args: 1,
+        js: "x => x === null ? null : Math.log10(x)",
+    },
+    Function {
+        name: "pow",
+        args: 2,
+        js: "(x, y) => x === null || y === null ? null : Math.pow(x, y)",
+    },
+    Function {
+        name: "min",
+        args: 2,
+        js: "(x, y) => x === null || y === null ? null : Math.min(x, y)",
+    },
+    Function {
+        name: "max",
+        args: 2,
+        js: "(x, y) => x === null || y === null ? null : Math.max(x, y)",
+    },
+    Function {
+        name: "string",
+        args: 1,
+        js: "x => x === null ? null : String(x)",
+    },
+    Function {
+        name: "number",
+        args: 1,
+        js: "x => x === null ? null : Number(x)",
+    },
+    Function {
+        name: "length",
+        args: 1,
+        js: "x => x === null ? null : x.size ?? x.length",
+    },
+    Function {
+        name: "lower",
+        args: 1,
+        js: "s => s === null ? null : s.toLowerCase()",
+    },
+    Function {
+        name: "upper",
+        args: 1,
+        js: "s => s === null ? null : s.toUpperCase()",
+    },
+    Function {
+        name: "trim",
+        args: 1,
+        js: "s => s === null ? null : s.trim()",
+    },
+    Function {
+        name: "substring",
+        args: 3,
+        js: "(s, start, end) => s === null || start === null || end === null ? null : s.substring(start, end)",
+    },
+    Function {
+        name: "contains",
+        args: 2,
+        js: "(s, x) => s === null || x === null ? null : s.includes(x)",
+    },
+    Function {
+        name: "starts_with",
+        args: 2,
+        js: "(s, prefix) => s === null || prefix === null ? null : s.startsWith(prefix)",
+    },
+    Function {
+        name: "ends_with",
+        args: 2,
+        js: "(s, suffix) => s === null || suffix === null ? null : s.endsWith(suffix)",
+    },
+    Function {
+        name: "split",
+        args: 2,
+        js: "(s, separator) => s === null || separator === null ? null : s.split(separator)",
+    },
+    Function {
+        name: "replace",
+        args: 3,
+        js: "(s, pattern, replacement) => s === null || pattern === null || replacement === null ? null : s.replaceAll(pattern, replacement)",
+    },
+    Function {
+        name: "regex_match",
+        args: 2,
+        js: "(s, pattern) => s === null || pattern === null ? null : new RegExp(pattern).test(s)",
+    },
+    Function {
+        name: "parse_date",
+        args: 1,
+        js: r#"s => {
+    if (s === null) return null;
+    const time = Date.parse(s);
+    return Number.isNaN(time) ? null : time;
+}"#,
+    },
+    Function {
+        name: "format_date",
+        args: 1,
+        js: "t => t === null ? null : new Date(t).toISOString()",
+    },
+    Function {
+        name: "year",
+        args: 1,
+        js: "t => t === null ? null : new Date(t).getUTCFullYear()",
+    },
+    Function {
+        name: "month",
+        args: 1,
+        js: "t => t === null ? null : new Date(t).getUTCMonth() + 1",
+    },
+    Function {
+        name: "day",
+        args: 1,
+        js: "t => t === null ? null : new Date(t).getUTCDate()",
+    },
+    Function {
+        name: "weekday",
+        args: 1,
+        js: "t => t === null ? null : new Date(t).getUTCDay()",
+    },
+    Function {
+        name: "hour",
+        args: 1,
+        js: "t => t === null ? null : new Date(t).getUTCHours()",
+    },
+    Function {
+        name: "minute",
+        args: 1,
+        js: "t => t === null ? null : new Date(t).getUTCMinutes()",
+    },
+    Function {
+        name: "second",
+        args: 1,
+        js: "t => t === null ? null : new Date(t).getUTCSeconds()",
+    },
+];
+
+/// Look up a built-in function by name.
+pub fn function(name: &str) -> Option<&'static Function> {
+    FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+/// JavaScript object expression containing all built-in functions, keyed by
+/// name. This is what compiled code expects as `__percival.functions`.
+pub fn functions_js() -> String {
+    let fields: Vec<_> = FUNCTIONS
+        .iter()
+        .map(|f| format!("{}: ({}),", f.name, f.js))
+        .collect();
+    format!("{{\n{}\n}}", fields.join("\n"))
+}
@@ -0,0 +1,679 @@
+use std::time::Duration;
+
+use percival::{
+    codegen::{compile, compile_with, Backend, CompileOptions, Error},
+    errors::format_expansion_error,
+    parser::Grammar,
+};
+
+#[test]
+fn compile_native_backend() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "
+edge(x: 2, y: 3).
+tc(x, y) :- edge(x, y).
+tc(x, y) :- tc(x, y: z), edge(x: z, y).
+",
+        )
+        .unwrap();
+
+    let js = compile(&prog).unwrap();
+    assert!(js.contains("__percival.Immutable"));
+
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert!(!js.contains("Immutable"));
+    assert!(js.contains("new Map()"));
+}
+
+#[test]
+fn compile_native_aggregate() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse("ok(value: sum[min[to] { edge(from, to) }] { vertex(id: from) }).")
+        .unwrap();
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert!(!js.contains("Immutable"));
+    assert!(js.contains("__percival.aggregates.sum("));
+}
+
+#[test]
+fn parse_backend_name() {
+    assert_eq!("native".parse(), Ok(Backend::Native));
+    assert_eq!("immutable".parse(), Ok(Backend::Immutable));
+    assert!("mutable".parse::<Backend>().is_err());
+}
+
+#[test]
+fn compile_limits() {
+    let grammar = Grammar::new();
+    let prog = grammar.parse("n(x: 0).\nn(x: `x + 1`) :- n(x).").unwrap();
+
+    let js = compile(&prog).unwrap();
+    assert!(!js.contains("LimitError"));
+
+    let options = CompileOptions::new()
+        .max_iterations(100)
+        .max_tuples(5000)
+        .time_limit(Duration::from_secs(2));
+    let js = compile_with(&prog, &options).unwrap();
+    assert!(js.contains("LimitError"));
+    assert!(js.contains("\"iterations\", 100"));
+    assert!(js.contains("\"tuples\", 5000, \"n\""));
+    assert!(js.contains("Date.now() + 2000"));
+    assert!(js.contains("\"time\", 2000, "));
+    assert!(js.contains("% 1024 === 0) __percival_check_limits();"));
+
+    let js = compile_with(&prog, &CompileOptions::new().max_iterations(100)).unwrap();
+    assert!(!js.contains("__percival_check_limits"));
+
+    // Iterations are counted separately for each stratum.
+    let prog = grammar
+        .parse("n(x: 0).\nn(x: `x + 1`) :- n(x).\nm(x) :- n(x).")
+        .unwrap();
+    let js = compile_with(&prog, &CompileOptions::new().max_iterations(100)).unwrap();
+    assert_eq!(js.matches("__percival_iterations = 0;").count(), 2);
+}
+
+#[test]
+fn compile_indices_after_bindings() {
+    let grammar = Grammar::new();
+    // Variables bound by local bindings and used in aggregate arguments are
+    // looked up through indices, which must be created.
+    let prog = grammar
+        .parse(
+            "
+p(x, y) :- a(x), y = `x + 1`, b(x: y).
+q(x, m: percentile[v, max[w] { c(x, w) }] { b(x: v) }) :- a(x).
+",
+        )
+        .unwrap();
+    for backend in [Backend::Immutable, Backend::Native] {
+        let js = compile_with(&prog, &CompileOptions::new().backend(backend)).unwrap();
+        assert!(js.contains("__percival_b_index"));
+        assert!(js.contains("__percival_c_index"));
+    }
+}
+
+#[test]
+fn compile_strata() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "
+tc(x, y) :- edge(x, y).
+tc(x, y: z) :- tc(x, y), edge(x: y, y: z).
+reach(x, n: count[y] { tc(x, y) }) :- node(x).
+",
+        )
+        .unwrap();
+    let js = compile(&prog).unwrap();
+    assert!(js.find("__percival_tc_new").unwrap() < js.find("__percival_reach_new").unwrap());
+
+    let prog = grammar
+        .parse("n(x: count[y] { n(x: y) }) :- seed(x).")
+        .unwrap();
+    let err = compile(&prog).unwrap_err();
+    assert!(matches!(err, Error::CircularReference(_)));
+    assert_eq!(
+        err.to_string(),
+        "Relation \"n\" is aggregated over in its own recursive definition",
+    );
+}
+
+#[test]
+fn compile_repeated_variable() {
+    let grammar = Grammar::new();
+    let prog = grammar.parse("same(x) :- e(x, y: x).").unwrap();
+    for backend in [Backend::Immutable, Backend::Native] {
+        let js = compile_with(&prog, &CompileOptions::new().backend(backend)).unwrap();
+        assert!(!js.contains("__percival_e_index"));
+    }
+}
+
+#[test]
+fn compile_string_escapes() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(r#"s(a: "say \"hi\"\n", b: "\\", c: "\u2028", d: "é")."#)
+        .unwrap();
+    let js = compile(&prog).unwrap();
+    assert!(js.contains(r#"a: "say \"hi\"\n""#));
+    assert!(js.contains(r#"b: "\\""#));
+    assert!(js.contains(r#"c: "\u2028""#));
+    assert!(js.contains("d: \"\u{e9}\""));
+}
+
+#[test]
+fn compile_custom_aggregates() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "
+aggregate product `results => results.reduce((x, y) => x * y, 1)`
+total(value: product[x] { num(x) }, n: size[x] { num(x) }).
+",
+        )
+        .unwrap();
+
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::UnknownAggregate(name)) if name == "size",
+    ));
+
+    let options = CompileOptions::new().aggregate("size", "results => results.length");
+    let js = compile_with(&prog, &options).unwrap();
+    assert!(js.contains("__percival_custom_aggregates.product("));
+    assert!(js.contains("__percival_custom_aggregates.size("));
+
+    let options = CompileOptions::new().aggregate("product", "results => 0");
+    assert!(matches!(
+        compile_with(&prog, &options),
+        Err(Error::DuplicateAggregate(name)) if name == "product",
+    ));
+
+    let prog = grammar.parse("aggregate sum `results => 0`").unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::ShadowedAggregate(name)) if name == "sum",
+    ));
+    let prog = grammar.parse("").unwrap();
+    let options = CompileOptions::new().aggregate("max", "results => 0");
+    assert!(matches!(
+        compile_with(&prog, &options),
+        Err(Error::ShadowedAggregate(name)) if name == "max",
+    ));
+}
+
+#[test]
+fn compile_builtin_aggregates() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"best(car: argmax[mpg] { cars(name, mpg) }, p: percentile[mpg, 0.9] { cars(mpg) })."#,
+        )
+        .unwrap();
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert!(js.contains(".push([mpg, {mpg: mpg, name: name}]);"));
+    assert!(js.contains(", 0.9)"));
+
+    let prog = grammar
+        .parse("bad(value: percentile[mpg] { cars(mpg) }).")
+        .unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::AggregateArity(name, 1, 0)) if name == "percentile",
+    ));
+}
+
+#[test]
+fn compile_multi_aggregate() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "stats(total, n) :- [total = sum[mpg], n = count[1]] { cars(mpg) }, limits(n, total).",
+        )
+        .unwrap();
+    let js = compile(&prog).unwrap();
+    assert!(js.contains("const [total, n] = "));
+    assert_eq!(
+        js.matches("for (const __percival_obj of __percival_cars")
+            .count(),
+        1
+    );
+
+    let prog = grammar
+        .parse("stats(n) :- [n = sum[mpg], n = count[1]] { cars(mpg) }.")
+        .unwrap();
+    assert!(matches!(compile(&prog), Err(Error::DuplicateVariable(_))));
+}
+
+#[test]
+fn compile_lattice() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "
+dist(x, d: min[0]) :- source(x).
+dist(x: y, d: min[`d + w`]) :- dist(x, d), edge(x, y, w).
+far(n: count[x] { dist(x, d), `d > 10` }).
+",
+        )
+        .unwrap();
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert!(js.contains("__percival_key(__percival_obj, \"d\")"));
+    assert!(js.contains("dist: Array.from(__percival_dist_best"));
+    assert!(compile(&prog).is_ok());
+
+    let prog = grammar
+        .parse("dist(x, d: min[0]) :- source(x).\ndist(x, d: max[1]) :- source(x).")
+        .unwrap();
+    assert!(matches!(compile(&prog), Err(Error::LatticeConflict(_))));
+
+    let prog = grammar.parse("dist(x, d: avg[0]) :- source(x).").unwrap();
+    assert!(matches!(compile(&prog), Err(Error::UnknownLattice(_))));
+
+    let prog = grammar
+        .parse("n(x: count[y] { n(x: y) }) :- seed(x).")
+        .unwrap();
+    assert!(matches!(compile(&prog), Err(Error::CircularReference(_))));
+}
+
+#[test]
+fn compile_list_and_record() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse("first(x) :- pair(p: [x, {b: 2}]).\nlit(p: [1, {b: 2}]).")
+        .unwrap();
+
+    let js = compile(&prog).unwrap();
+    assert!(js.contains("__percival_pair_0.add(__percival.Immutable.Map(__percival_obj));"));
+    assert!(js.contains(
+        "const __percival_nested_6 = __percival.Immutable.fromJS(__percival_obj.get('p', null));"
+    ));
+    assert!(js.contains("__percival.Immutable.List.isList(__percival_nested_6)"));
+    assert!(js.contains("const x = __percival_nested_6.get(0);"));
+    assert!(js.contains(
+        "__percival.Immutable.is(__percival.Immutable.fromJS(__percival_nested_6.get(1)), "
+    ));
+    assert!(js.contains("p: __percival.Immutable.List([1, __percival.Immutable.Map({b: 2})])"));
+
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert!(js.contains(
+        "Array.isArray((__percival_obj.p ?? null)) && (__percival_obj.p ?? null).length === 2"
+    ));
+    assert!(js.contains("p: Object.freeze([1, Object.freeze({b: 2})])"));
+    assert!(js.contains("!Number.isFinite(v)"));
+}
+
+#[test]
+fn compile_null_joins() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "
+missing(name) :- person(name, email: null).
+joined(name, other) :- person(name, email), person(name: other, email).
+same(name) :- person(name, email, backup: email).
+",
+        )
+        .unwrap();
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert!(js.contains("JSON.stringify([null], __percival_canon)"));
+    assert!(js.contains("email === null ? [] : "));
+    assert!(js.contains("const email = (__percival_obj.backup ?? null);"));
+    assert!(js.contains("if (!(email !== null && "));
+}
+
+#[test]
+fn compile_disjunction() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "
+link(x, y) :- (edge(x, y); edge(x: y, y: x)).
+n(value: count[x] { (link(x); vertex(id: x)) }).
+",
+        )
+        .unwrap();
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert_eq!(
+        js.matches("const y = (__percival_obj.x ?? null);").count(),
+        1
+    );
+    assert_eq!(js.matches(".push(x);").count(), 2);
+    assert_eq!(
+        js.matches("__percival_key({x: x}), true).size) {").count(),
+        2
+    );
+
+    // Variables bound in only one branch can be bound again, but not read.
+    let prog = grammar.parse("p(x, y) :- (a(x, y); b(x)), c(y).").unwrap();
+    assert!(compile(&prog).is_ok());
+    let prog = grammar.parse("p(x, y) :- (a(x, y); b(x)).").unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::PartialDisjunction(name)) if name == "y",
+    ));
+    let prog = grammar.parse("p(x) :- (a(x, y); b(x)), `y > 0`.").unwrap();
+    assert!(matches!(compile(&prog), Err(Error::PartialDisjunction(_))));
+}
+
+#[test]
+fn compile_native_expressions() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "
+big(x, label: \"n=\" + string(x)) :- n(x), x >= 2 && !(x == 3).
+next(x: y) :- n(x), y = x + 1, n(x: y + 1).
+",
+        )
+        .unwrap();
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert!(js.contains("if (((x === null ? false : (x >= 2)) && (!(x === null ? false : "));
+    assert!(
+        js.contains("(__percival_lhs + __percival_rhs))(\"n=\", (x === null ? null : __percival.functions.string(x)))")
+    );
+    assert!(js.contains("const y = (x === null ? null : (x + 1));"));
+    assert!(js.contains("JSON.stringify([(y === null ? null : (y + 1))], __percival_canon)"));
+
+    // Function calls are null if any of their arguments are null.
+    let prog = grammar
+        .parse("p(a: abs(x), s: substring(name, 0, x + 1), n: length([x])) :- q(x, name).")
+        .unwrap();
+    let js = compile_with(&prog, &CompileOptions::new().backend(Backend::Native)).unwrap();
+    assert!(js.contains("a: (x === null ? null : __percival.functions.abs(x))"));
+    assert!(js.contains(
+        "((__percival_arg0, __percival_arg1, __percival_arg2) => __percival_arg0 === null \
+         || __percival_arg1 === null || __percival_arg2 === null ? null : \
+         __percival.functions.substring(__percival_arg0, __percival_arg1, __percival_arg2))\
+         (name, 0, (x === null ? null : (x + 1)))"
+    ));
+    assert!(js.contains("n: __percival.functions.length(Object.freeze([x]))"));
+
+    let prog = grammar.parse("p(y) :- n(x), y = sqrt(x, 2).").unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::FunctionArity(name, 1, 2)) if name == "sqrt",
+    ));
+    let prog = grammar.parse("p(y) :- n(x), y = cbrt(x).").unwrap();
+    assert!(matches!(compile(&prog), Err(Error::UnknownFunction(_))));
+}
+
+#[test]
+fn compile_string_and_date_functions() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+names(name: lower(name), year: year(parse_date(born))) :-
+  person(name, born),
+  starts_with(name, "A") || regex_match(name, "^b"),
+  length(split(name, " ")) > 1.
+"#,
+        )
+        .unwrap();
+    let js = compile(&prog).unwrap();
+    assert!(js.contains("__percival.functions.starts_with(name, \"A\")"));
+    assert!(
+        js.contains("__percival.functions.length(__percival_lhs))(__percival.Immutable.fromJS(")
+    );
+
+    let prog = grammar
+        .parse("p(s) :- q(name), s = substring(name, 1).")
+        .unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::FunctionArity(name, 3, 2)) if name == "substring",
+    ));
+}
+
+#[test]
+fn compile_sandboxed() {
+    let grammar = Grammar::new();
+    let options = CompileOptions::new().backend(Backend::Native).sandbox(true);
+
+    // Backticks are compiled as native expressions when they parse as one.
+    let prog = grammar
+        .parse("n(x: 0).\nn(x: `x + 1`) :- n(x), `x < 10`.")
+        .unwrap();
+    let js = compile_with(&prog, &options).unwrap();
+    assert!(js.contains("x: (x === null ? null : (x + 1))"));
+    assert!(js.contains("if ((x === null ? false : (x < 10))) {"));
+
+    let prog = grammar
+        .parse("p(x: `fetch(\"https://example.com\")`) :- q(x).")
+        .unwrap();
+    assert!(compile(&prog).is_ok());
+    assert!(matches!(
+        compile_with(&prog, &options),
+        Err(Error::UnknownFunction(name)) if name == "fetch",
+    ));
+
+    let prog = grammar
+        .parse("p(x) :- q(x), `globalThis.alert(x)`.")
+        .unwrap();
+    assert!(matches!(
+        compile_with(&prog, &options),
+        Err(Error::SandboxedExpression(_)),
+    ));
+
+    let prog = grammar
+        .parse("aggregate evil `results => fetch(results)`\np(n: count[x] { q(x) }).")
+        .unwrap();
+    assert!(matches!(
+        compile_with(&prog, &options),
+        Err(Error::SandboxedAggregate(name)) if name == "evil",
+    ));
+    let options = options.aggregate("product", "results => results.reduce((x, y) => x * y, 1)");
+    let prog = grammar.parse("p(n: product[x] { q(x) }).").unwrap();
+    assert!(compile_with(&prog, &options).is_ok());
+}
+
+#[test]
+fn compile_import_resolvers() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+import cars from "catalog://cars"
+import airports from "npm://vega-datasets@2.2.0/data/airports.csv"
+"#,
+        )
+        .unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::UnknownProtocol(scheme)) if scheme == "catalog",
+    ));
+
+    let options = CompileOptions::new().resolver("catalog", |uri: &str| {
+        match uri.strip_prefix("catalog://") {
+            Some("cars") => Ok("https://example.com/cars.json".into()),
+            _ => Err("not in catalog".into()),
+        }
+    });
+    let js = compile_with(&prog, &options).unwrap();
+    assert!(js.contains("cars: await __percival.load(\"https://example.com/cars.json\")"));
+    assert!(js.contains("https://cdn.jsdelivr.net/npm/vega-datasets@2.2.0/data/airports.csv"));
+
+    let prog = grammar.parse(r#"import x from "catalog://x""#).unwrap();
+    assert!(matches!(
+        compile_with(&prog, &options),
+        Err(Error::ImportResolution(uri, msg)) if uri == "catalog://x" && msg == "not in catalog",
+    ));
+
+    // Registered resolvers take precedence over built-in ones.
+    let options = CompileOptions::new().resolver("npm", |_: &str| Ok("data:,[]".to_string()));
+    let prog = grammar.parse(r#"import x from "npm://x""#).unwrap();
+    assert!(compile_with(&prog, &options)
+        .unwrap()
+        .contains("load(\"data:,[]\")"));
+}
+
+#[test]
+fn compile_import_formats() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+import cars from "https://example.com/cars.txt" as csv(header: false, types: {column2: "date"})
+import logs from "https://example.com/logs.json"
+"#,
+        )
+        .unwrap();
+    let js = compile(&prog).unwrap();
+    assert!(js.contains(
+        r#"cars: await __percival.load("https://example.com/cars.txt", {format: "csv", header: false, types: {column2: "date"}}),"#
+    ));
+    assert!(js.contains(r#"logs: await __percival.load("https://example.com/logs.json"),"#));
+
+    let prog = grammar
+        .parse(r#"import x from "https://example.com/x" as parquet"#)
+        .unwrap();
+    assert!(matches!(compile(&prog), Err(Error::UnknownFormat(_))));
+
+    for options in [
+        "json(header: true)",
+        "csv(delimiter: \"ab\")",
+        "tsv(types: {a: \"int\"})",
+    ] {
+        let src = format!(r#"import x from "https://example.com/x" as {}"#, options);
+        let prog = grammar.parse(&src).unwrap();
+        assert!(matches!(
+            compile(&prog),
+            Err(Error::InvalidFormatOption(..))
+        ));
+    }
+}
+
+#[test]
+fn compile_import_fields() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+import cars(name: Name, mpg: "Miles per Gallon") from "https://example.com/cars.json"
+efficient(name) :- cars(name, mpg), mpg > 30.
+"#,
+        )
+        .unwrap();
+    let js = compile(&prog).unwrap();
+    assert!(js.contains(
+        r#"cars: (await __percival.load("https://example.com/cars.json")).map((__percival_obj) => ({mpg: __percival_obj["Miles per Gallon"], name: __percival_obj["Name"]})),"#
+    ));
+
+    let prog = grammar
+        .parse(
+            r#"
+import cars(name: Name) from "https://example.com/cars.json"
+heavy(name) :- cars(name, weight), weight > 4000.
+"#,
+        )
+        .unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::UnselectedField(name, field)) if name == "cars" && field == "weight"
+    ));
+
+    // Facts in aggregates of the goal are also checked.
+    let prog = grammar
+        .parse(
+            r#"
+import cars(name: Name) from "https://example.com/cars.json"
+total(n: sum[weight] { cars(weight) }).
+"#,
+        )
+        .unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::UnselectedField(name, field)) if name == "cars" && field == "weight"
+    ));
+}
+
+#[test]
+fn compile_import_sha256() {
+    let grammar = Grammar::new();
+    let hash = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    let prog = grammar
+        .parse(&format!(
+            r#"
+import logs from "https://example.com/logs.json" sha256 "{hash}"
+import cars from "https://example.com/cars" sha256 "{hash}" as tsv
+"#,
+            hash = hash,
+        ))
+        .unwrap();
+    let js = compile(&prog).unwrap();
+    assert!(js.contains(&format!(
+        r#"logs: await __percival.load("https://example.com/logs.json", {{sha256: "{}"}}),"#,
+        hash,
+    )));
+    assert!(js.contains(&format!(
+        r#"cars: await __percival.load("https://example.com/cars", {{format: "tsv", sha256: "{}"}}),"#,
+        hash,
+    )));
+}
+
+#[test]
+fn compile_visibility() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+import cars from "https://example.com/cars.json"
+private edge(x: 1, y: 2).
+path(x, y) :- edge(x, y).
+"#,
+        )
+        .unwrap();
+    let js = compile(&prog).unwrap();
+    let output = &js[js.rfind("return").unwrap()..];
+    assert!(output.contains("cars:") && output.contains("path:"));
+    assert!(!output.contains("edge:"));
+
+    let prog = grammar
+        .parse(
+            r#"
+export path(x, y) :- edge(x, y).
+private path(x: 1, y: 1).
+"#,
+        )
+        .unwrap();
+    assert!(matches!(
+        compile(&prog),
+        Err(Error::VisibilityConflict(name)) if name == "path"
+    ));
+}
+
+#[test]
+fn compile_template_limits() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            "
+template close(E, R) {
+    step(x, y) :- E(x, y).
+    R(x, y) :- step(x, y).
+    R(x, y) :- R(x, y: z), step(x: z, y).
+}
+close!(edge, path).
+",
+        )
+        .unwrap();
+    let options = CompileOptions::new()
+        .backend(Backend::Native)
+        .max_iterations(100)
+        .max_tuples(5000)
+        .time_limit(Duration::from_secs(1));
+    let js = compile_with(&prog, &options).unwrap();
+    for limit in ["\"iterations\", 100", "\"time\", 1000"] {
+        let guard = format!("({}, __percival_growing({{\"close!1_step\": ", limit);
+        assert!(js.contains(&guard), "{}", guard);
+    }
+    assert!(js.contains("\"tuples\", 5000, \"close!1_step\""));
+    assert!(!js.contains("close!1_step:"));
+}
+
+#[test]
+fn compile_template_errors() {
+    let grammar = Grammar::new();
+    let src = "template t(R) { R(x: bogus(1)). }\nt!(a).\n";
+    let prog = grammar.parse(src).unwrap();
+    let err = compile(&prog).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Function \"bogus\" is not built in \
+         (in template \"t\" declared at 0..34 and instantiated at 34..41)",
+    );
+
+    let no_modules = |_: &str| Err(String::new());
+    let report = format_expansion_error(src, &err, &no_modules).unwrap();
+    assert!(report.contains("Function \"bogus\" is not built in"));
+    assert!(report.contains("In this instance of template"));
+    assert!(report.contains("is declared here"));
+    assert!(
+        format_expansion_error(src, &Error::UnknownFunction("f".into()), &no_modules).is_none()
+    );
+}
@@ -0,0 +1,75 @@
+use percival::{
+    lint::{check, Warning},
+    parser::Grammar,
+};
+
+#[test]
+fn lint_value_invention() {
+    let grammar = Grammar::new();
+    let prog = grammar.parse("n(x: 0).\nn(x: `x + 1`) :- n(x).").unwrap();
+    assert_eq!(
+        check(&prog),
+        vec![Warning::ValueInvention {
+            relation: "n".into(),
+            field: "x".into(),
+            vars: ["x".into()].into_iter().collect(),
+            origin: Default::default(),
+        }],
+    );
+
+    // Invented values can also flow through bindings and mutual recursion.
+    let prog = grammar
+        .parse(
+            "
+a(x) :- b(y), x = `y * 2`.
+b(y) :- a(x: y).
+",
+        )
+        .unwrap();
+    assert_eq!(check(&prog).len(), 1);
+
+    // Building ever-longer lists from recursive values also never terminates.
+    let prog = grammar
+        .parse("path(x, p: [x]) :- node(x).\npath(x: y, p: [y, p]) :- path(x, p), edge(x, y).")
+        .unwrap();
+    assert_eq!(check(&prog).len(), 1);
+}
+
+#[test]
+fn lint_bounded_recursion() {
+    let grammar = Grammar::new();
+    let text = "
+fib(n: 0, x: 0).
+fib(n: 1, x: 1).
+fib(n: `n + 1`, x) :-
+  fib(n, x: x1),
+  fib(n: `n - 1`, x: x2),
+  x = `x1 + x2`,
+  `n < 25`.
+";
+    assert!(check(&grammar.parse(text).unwrap()).is_empty());
+
+    // Native conditions are visible to the analysis, unlike raw JavaScript.
+    let text = "
+fib(n: n + 1, x) :- fib(n, x: x1), fib(n: n - 1, x: x2), x = x1 + x2, n < 25.
+";
+    assert!(check(&grammar.parse(text).unwrap()).is_empty());
+    let text = "n(x: 0).\nn(x: y) :- n(x), y = x + 1.";
+    assert_eq!(check(&grammar.parse(text).unwrap()).len(), 1);
+
+    // A condition on a recursive variable that the computed field does not
+    // come from leaves it unbounded.
+    let text = "n(k: 0, x: 0).\nn(k, x: x + 1) :- n(k, x), k < 5.";
+    assert_eq!(check(&grammar.parse(text).unwrap()).len(), 1);
+    let text = "n(k: 0, x: 0).\nn(k, x: `x + 1`) :- n(k, x), `k < 5`.";
+    assert_eq!(check(&grammar.parse(text).unwrap()).len(), 1);
+    let text = "n(x: 0).\nn(x: y) :- n(x), y = x + 1, y < 10.";
+    assert!(check(&grammar.parse(text).unwrap()).is_empty());
+
+    let text = "
+tc(x, y) :- edge(x, y).
+tc(x, y) :- tc(x, y: z), edge(x: z, y).
+double(x: `2 * x`) :- tc(x).
+";
+    assert!(check(&grammar.parse(text).unwrap()).is_empty());
+}
@@ -0,0 +1,182 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use maplit::{btreemap, btreeset};
+use percival::{
+    ast::Use,
+    codegen::{self, compile, compile_with, CompileOptions},
+    lint::{check, Warning},
+    module::{link, Error},
+    parser::Grammar,
+};
+
+fn loader(files: BTreeMap<&'static str, &'static str>) -> impl Fn(&str) -> Result<String, String> {
+    move |path: &str| {
+        files
+            .get(path)
+            .map(|src| src.to_string())
+            .ok_or_else(|| "file not found".into())
+    }
+}
+
+#[test]
+fn link_namespaced_module() {
+    let grammar = Grammar::new();
+    let files = loader(btreemap! {
+        "lib/graph.pcv" => r#"
+use "util.pcv"
+export path(x, y) :- edge(x, y).
+export path(x, z) :- path(x, y), edge(x: y, y: z).
+private hub(x) :- util.degree(x, n), n > 1.
+"#,
+        "lib/util.pcv" => "degree(x, n: count[1] { edge(x) }) :- edge(x).",
+    });
+    let prog = grammar
+        .parse(
+            r#"
+use "lib/graph.pcv"
+use "lib/util.pcv" as stats
+reach(x, y) :- graph.path(x, y).
+busy(x) :- stats.degree(x, n), n > 2.
+"#,
+        )
+        .unwrap();
+    let prog = link(&prog, &files).unwrap();
+    assert!(prog.uses.is_empty());
+    assert_eq!(prog.deps(), btreeset! {"edge".into()});
+    assert_eq!(prog.results(), btreeset! {"reach".into(), "busy".into()});
+    assert_eq!(
+        prog.relations(),
+        btreeset! {
+            "reach".into(),
+            "busy".into(),
+            "graph.path".into(),
+            "graph.hub".into(),
+            "graph.util.degree".into(),
+            "stats.degree".into(),
+        },
+    );
+    assert!(compile(&prog).is_ok());
+}
+
+#[test]
+fn link_shared_module() {
+    let grammar = Grammar::new();
+    let files = loader(btreemap! {
+        "a.pcv" => "use \"c.pcv\"\nexport left(x) :- c.head(x).",
+        "b.pcv" => "use \"c.pcv\"\nexport right(x) :- c.head(x).",
+        "c.pcv" => "aggregate first `xs => xs[0]`\nexport head(x: first[x] { item(x) }).",
+    });
+    let prog = grammar
+        .parse("use \"a.pcv\"\nuse \"b.pcv\"\nboth(x) :- a.left(x), b.right(x).")
+        .unwrap();
+    let prog = link(&prog, &files).unwrap();
+    assert_eq!(prog.aggregates.len(), 1);
+    assert!(prog.relations().contains("a.c.head"));
+    assert!(prog.relations().contains("b.c.head"));
+    assert!(compile(&prog).is_ok());
+}
+
+#[test]
+fn compile_module_limits() {
+    let grammar = Grammar::new();
+    let files = loader(btreemap! {
+        "graph.pcv" => "export path(x, y) :- edge(x, y).\nexport path(x, z) :- path(x, y), edge(x: y, y: z).",
+    });
+    let prog = grammar
+        .parse("use \"graph.pcv\"\nreach(x, y) :- graph.path(x, y).")
+        .unwrap();
+    let prog = link(&prog, &files).unwrap();
+    let options = CompileOptions::new()
+        .max_iterations(100)
+        .time_limit(Duration::from_secs(1));
+    let js = compile_with(&prog, &options).unwrap();
+    // Qualified names are quoted in the objects reported by the limit guards.
+    for limit in ["\"iterations\", 100", "\"time\", 1000"] {
+        let guard = format!("({}, __percival_growing({{\"graph.path\": ", limit);
+        assert!(js.contains(&guard), "{}", guard);
+    }
+    assert!(!js.contains("{graph.path:"));
+}
+
+#[test]
+fn module_diagnostics() {
+    let grammar = Grammar::new();
+    let files = loader(btreemap! {
+        "lib/graph.pcv" => "use \"count.pcv\"\nexport path(x, y) :- edge(x, y).",
+        "lib/count.pcv" => "export n(x: `x + 1`) :- n(x).\nbad(x: bogus(x)) :- edge(x).",
+    });
+    let prog = grammar
+        .parse("use \"lib/graph.pcv\"\nreach(x, y) :- graph.path(x, y).")
+        .unwrap();
+    let prog = link(&prog, &files).unwrap();
+    assert!(prog.rules[0].origin.is_main());
+
+    let warnings = check(&prog);
+    assert_eq!(warnings.len(), 1);
+    assert!(matches!(
+        &warnings[0],
+        Warning::ValueInvention { origin, .. }
+            if origin.module.as_deref() == Some("lib/count.pcv")
+    ));
+
+    let err = compile(&prog).unwrap_err();
+    assert!(matches!(
+        &err,
+        codegen::Error::Located(err, origin)
+            if matches!(**err, codegen::Error::UnknownFunction(_))
+                && origin.module.as_deref() == Some("lib/count.pcv")
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Function \"bogus\" is not built in (in module \"lib/count.pcv\")",
+    );
+}
+
+#[test]
+fn link_errors() {
+    let grammar = Grammar::new();
+    let files = loader(btreemap! {
+        "a.pcv" => r#"use "dir/b.pcv""#,
+        "dir/b.pcv" => r#"use "../a.pcv""#,
+        "graph.pcv" => "private step(x, y) :- edge(x, y).\npath(x, y) :- step(x, y).",
+        "broken.pcv" => "path(x, y) :- ",
+    });
+    let check = |src: &str| link(&grammar.parse(src).expect(src), &files).unwrap_err();
+
+    assert!(matches!(
+        check(r#"use "a.pcv""#),
+        Error::Cycle(path, cycle) if path == "a.pcv" && cycle == "a.pcv -> dir/b.pcv -> a.pcv"
+    ));
+    assert!(matches!(
+        check("use \"graph.pcv\"\nok(x) :- graph.step(x, y)."),
+        Error::UnknownRelation(namespace, name) if namespace == "graph" && name == "step"
+    ));
+    assert!(matches!(
+        check("ok(x) :- graph.path(x, y)."),
+        Error::UnknownNamespace(namespace) if namespace == "graph"
+    ));
+    assert!(matches!(
+        check("use \"graph.pcv\"\ngraph.path(x: 1, y: 2)."),
+        Error::ModuleGoal(name) if name == "graph.path"
+    ));
+    assert!(matches!(
+        check("use \"graph.pcv\"\nuse \"a.pcv\" as graph"),
+        Error::DuplicateNamespace(namespace) if namespace == "graph"
+    ));
+    assert!(matches!(check(r#"use "missing.pcv""#), Error::Load(path, _) if path == "missing.pcv"));
+    assert!(matches!(check(r#"use "broken.pcv""#), Error::Parse(path, _) if path == "broken.pcv"));
+}
+
+#[test]
+fn module_namespace() {
+    let namespace = |path: &str| {
+        Use {
+            path: path.into(),
+            alias: None,
+        }
+        .namespace()
+    };
+    assert_eq!(namespace("lib/graph.pcv"), "graph");
+    assert_eq!(namespace("../graph-utils.v2.pcv"), "graph_utils_v2");
+    assert_eq!(namespace("2d.pcv"), "_2d");
+}
@@ -0,0 +1,115 @@
+use maplit::btreeset;
+use percival::{
+    notebook::{
+        evaluation_order, is_notebook, marshal, unmarshal, Cell, CellDeps, CellType, Error,
+    },
+    parser::Grammar,
+};
+
+fn cell(cell_type: CellType, hidden: bool, value: &str) -> Cell {
+    Cell {
+        cell_type,
+        hidden,
+        value: value.into(),
+    }
+}
+
+fn code(deps: &[&str], results: &[&str]) -> Option<CellDeps> {
+    Some(CellDeps {
+        deps: deps.iter().map(|name| name.to_string()).collect(),
+        results: results.iter().map(|name| name.to_string()).collect(),
+    })
+}
+
+#[test]
+fn notebook_marshal() {
+    let check = |cells: Vec<Cell>| {
+        let text = marshal(&cells);
+        assert!(is_notebook(&text));
+        assert_eq!(unmarshal(&text), cells);
+    };
+    check(vec![]);
+    check(vec![
+        cell(
+            CellType::Markdown,
+            false,
+            "Hello\n\nWorld123 **bold text**\n",
+        ),
+        cell(CellType::Markdown, true, "\n\t\n\n"),
+        cell(CellType::Code, false, "tc(x) :- \n\ny(x)."),
+        cell(CellType::Code, true, "\ny(x: 5). // initialize\n"),
+    ]);
+    check(vec![cell(
+        CellType::Markdown,
+        false,
+        "\r\nHello\n\r\nWorld123 \r\n\n**bold text**\r\n\n",
+    )]);
+    check(vec![cell(
+        CellType::Plot,
+        false,
+        r#"aapl => Plot.area(aapl, {x1: "Date", y1: 0, y2: "Close"}).plot()"#,
+    )]);
+
+    let text = "This is a Percival notebook (https://percival.ink/).\r\n\r\n╔═╣ Code\r\nedge(x: 1, y: 2).\r\n";
+    assert_eq!(
+        unmarshal(text),
+        vec![cell(CellType::Code, true, "edge(x: 1, y: 2).")],
+    );
+    assert!(!is_notebook("edge(x: 1, y: 2).\n"));
+}
+
+#[test]
+fn notebook_cell_deps() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse("private tc(x, y) :- edge(x, y).\nreach(x) :- tc(x: 1, y: x).")
+        .unwrap();
+    assert_eq!(
+        CellDeps::program(&prog),
+        CellDeps {
+            deps: btreeset! {"edge".into()},
+            results: btreeset! {"reach".into()},
+        },
+    );
+    assert_eq!(
+        CellDeps::plot("  cars =>\n Plot.dot(cars)"),
+        code(&["cars"], &[])
+    );
+    assert_eq!(CellDeps::plot("\n"), code(&[], &[]));
+    assert_eq!(CellDeps::plot("Plot.dot(cars)"), None);
+    assert_eq!(CellDeps::plot("2cars => null"), None);
+}
+
+#[test]
+fn notebook_evaluation_order() {
+    let cells = vec![
+        code(&["edge"], &["path"]),
+        None,
+        code(&["path"], &[]),
+        code(&[], &["edge"]),
+        code(&[], &["node"]),
+    ];
+    assert_eq!(evaluation_order(&cells), Ok(vec![3, 0, 2, 4]));
+
+    let cells = vec![code(&[], &["a"]), code(&[], &["a", "b"])];
+    assert_eq!(
+        evaluation_order(&cells),
+        Err(Error::DuplicateProducer("a".into(), vec![0, 1])),
+    );
+    let cells = vec![code(&["b"], &["a"])];
+    assert_eq!(
+        evaluation_order(&cells),
+        Err(Error::MissingDependency(0, "b".into())),
+    );
+    let cells = vec![
+        code(&[], &["a"]),
+        code(&["c"], &["b"]),
+        code(&["a", "b"], &["c"]),
+    ];
+    let err = evaluation_order(&cells).unwrap_err();
+    assert_eq!(err, Error::Cycle(vec![1, 2, 1]));
+    assert_eq!(
+        err.to_string(),
+        "Cells depend on each other cyclically: cell 2 -> cell 3 -> cell 2",
+    );
+}
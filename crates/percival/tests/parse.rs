@@ -1,7 +1,10 @@
-use maplit::btreemap;
+use maplit::{btreemap, btreeset};
 
 use percival::{
-    ast::{Aggregate, Clause, Fact, Import, Literal, Program, Rule, Value},
+    ast::{
+        Aggregate, AggregateOutput, Assert, BinaryOp, Clause, CustomAggregate, Fact, Import,
+        ImportFormat, Lattice, Literal, MultiAggregate, Origin, Program, Rule, UnaryOp, Use, Value,
+    },
     errors::format_errors,
     parser::Grammar,
 };
@@ -38,8 +41,10 @@ fn parse_single_rule() {
                         },
                     }),
                 ],
+                origin: Origin::default(),
             }],
             imports: vec![],
+            ..Default::default()
         },
     );
 }
@@ -65,18 +70,41 @@ fn parse_literal() {
                 goal: Fact {
                     name: "person".into(),
                     props: btreemap! {
-                        "name".into() => Value::Literal(Literal::String("eric\\t".into())),
+                        "name".into() => Value::Literal(Literal::String("eric\t".into())),
                         "age".into() => Value::Literal(Literal::Number("20".into())),
                         "weight".into() => Value::Literal(Literal::Number("1.234e+2".into())),
                     },
                 },
                 clauses: vec![],
+                origin: Origin::default(),
             }],
             imports: vec![],
+            ..Default::default()
         },
     );
 }
 
+#[test]
+fn parse_string_escapes() {
+    let grammar = Grammar::new();
+    let result =
+        grammar.parse(r#"s(a: "\"quoted\" \\ \/", b: "\u00e9\u2028", c: "\ud83d\ude00")."#);
+    assert_eq!(
+        result.unwrap().rules[0].goal.props,
+        btreemap! {
+            "a".into() => Value::Literal(Literal::String("\"quoted\" \\ /".into())),
+            "b".into() => Value::Literal(Literal::String("\u{e9}\u{2028}".into())),
+            "c".into() => Value::Literal(Literal::String("\u{1f600}".into())),
+        },
+    );
+
+    // Unpaired surrogates are not valid strings.
+    let text = r#"s(a: "\ud83d")."#;
+    let errors = grammar.parse(text).unwrap_err();
+    let message = format_errors(text, errors);
+    assert!(message.contains("Invalid unicode escape in string"));
+}
+
 #[test]
 fn parse_err() {
     let grammar = Grammar::new();
@@ -101,6 +129,21 @@ fn parse_reserved_word() {
     let errors = grammar.parse(text).unwrap_err();
     assert!(errors.len() == 1);
 
+    // Names with two leading underscores are reserved for generated code.
+    for text in [
+        "__assert_0(passed: true).",
+        "bad(x) :- a(x), __left = x.",
+        "bad(__right: 1).",
+    ] {
+        let errors = grammar.parse(text).unwrap_err();
+        let message = format_errors(text, errors);
+        assert!(
+            message.contains("are reserved for internal use"),
+            "{}",
+            text
+        );
+    }
+
     // It is okay to use a reserved word as a field name, just not a variable.
     let text = "ok(continue: x).";
     let result = grammar.parse(text);
@@ -131,8 +174,10 @@ fn parse_js_expr() {
                     }),
                     Clause::Expr("num < 10".into()),
                 ],
+                origin: Origin::default(),
             }],
             imports: vec![],
+            ..Default::default()
         },
     );
 }
@@ -189,8 +234,10 @@ fn parse_empty() {
                     name: "ok".into(),
                     props: btreemap! {},
                 })],
+                origin: Origin::default(),
             }],
             imports: vec![],
+            ..Default::default()
         },
     );
 }
@@ -214,17 +261,27 @@ import football from "gh://vega/vega-datasets@next/data/football.json"
             imports: vec![
                 Import {
                     name: "hello".into(),
-                    uri: "https://example.com/hello.json".into()
+                    uri: "https://example.com/hello.json".into(),
+                    format: None,
+                    sha256: None,
+                    fields: None,
                 },
                 Import {
                     name: "barley".into(),
-                    uri: "npm://vega-datasets/data/barley.json".into()
+                    uri: "npm://vega-datasets/data/barley.json".into(),
+                    format: None,
+                    sha256: None,
+                    fields: None,
                 },
                 Import {
                     name: "football".into(),
-                    uri: "gh://vega/vega-datasets@next/data/football.json".into()
+                    uri: "gh://vega/vega-datasets@next/data/football.json".into(),
+                    format: None,
+                    sha256: None,
+                    fields: None,
                 },
             ],
+            ..Default::default()
         },
     );
 }
@@ -246,8 +303,10 @@ fn parse_boolean() {
                     },
                 },
                 clauses: vec![],
+                origin: Origin::default(),
             }],
             imports: vec![],
+            ..Default::default()
         },
     );
 }
@@ -292,8 +351,10 @@ ok(val) :-
                     }),
                     Clause::Binding("val".into(), Value::Expr("3 * x".into())),
                 ],
+                origin: Origin::default(),
             }],
             imports: vec![],
+            ..Default::default()
         },
     );
 }
@@ -333,6 +394,7 @@ ok(value) :-
                         Value::Aggregate(Aggregate {
                             operator: "mean".into(),
                             value: Box::new(Value::Id("mpg".into())),
+                            args: vec![],
                             subquery: vec![Clause::Fact(Fact {
                                 name: "cars".into(),
                                 props: btreemap! {
@@ -343,8 +405,486 @@ ok(value) :-
                         }),
                     ),
                 ],
+                origin: Origin::default(),
             }],
             imports: vec![],
+            ..Default::default()
         },
     );
 }
+
+#[test]
+fn parse_custom_aggregate() {
+    let grammar = Grammar::new();
+    let result = grammar.parse(
+        r#"
+aggregate product `results => results.reduce((x, y) => x * y, 1)`
+total(value: product[x] { num(x) }).
+"#,
+    );
+    let prog = result.unwrap();
+    assert_eq!(
+        prog.aggregates,
+        vec![CustomAggregate {
+            name: "product".into(),
+            reducer: "results => results.reduce((x, y) => x * y, 1)".into(),
+        }],
+    );
+    assert_eq!(prog.rules.len(), 1);
+}
+
+#[test]
+fn parse_aggregate_args() {
+    let grammar = Grammar::new();
+    let result = grammar.parse(r#"p(value: percentile[mpg, 0.9] { cars(mpg) })."#);
+    let prog = result.unwrap();
+    assert_eq!(
+        prog.rules[0].goal.props["value"],
+        Value::Aggregate(Aggregate {
+            operator: "percentile".into(),
+            value: Box::new(Value::Id("mpg".into())),
+            args: vec![Value::Literal(Literal::Number("0.9".into()))],
+            subquery: vec![Clause::Fact(Fact {
+                name: "cars".into(),
+                props: btreemap! {
+                    "mpg".into() => Value::Id("mpg".into()),
+                },
+            })],
+        }),
+    );
+}
+
+#[test]
+fn parse_multi_aggregate() {
+    let grammar = Grammar::new();
+    let result =
+        grammar.parse("stats(total, n) :- [total = sum[mpg], n = count[1]] { cars(mpg) }.");
+    let prog = result.unwrap();
+    assert_eq!(
+        prog.rules[0].clauses,
+        vec![Clause::MultiAggregate(MultiAggregate {
+            outputs: vec![
+                AggregateOutput {
+                    name: "total".into(),
+                    operator: "sum".into(),
+                    value: Value::Id("mpg".into()),
+                    args: vec![],
+                },
+                AggregateOutput {
+                    name: "n".into(),
+                    operator: "count".into(),
+                    value: Value::Literal(Literal::Number("1".into())),
+                    args: vec![],
+                },
+            ],
+            subquery: vec![Clause::Fact(Fact {
+                name: "cars".into(),
+                props: btreemap! {
+                    "mpg".into() => Value::Id("mpg".into()),
+                },
+            })],
+        })],
+    );
+}
+
+#[test]
+fn parse_lattice() {
+    let grammar = Grammar::new();
+    let result = grammar.parse("dist(x: y, d: min[`d + w`]) :- dist(x, d), edge(x, y, w).");
+    let prog = result.unwrap();
+    assert_eq!(
+        prog.rules[0].goal.props["d"],
+        Value::Lattice(Lattice {
+            operator: "min".into(),
+            value: Box::new(Value::Expr("d + w".into())),
+        }),
+    );
+
+    assert!(grammar.parse("a(x) :- b(x: min[y]).").is_err());
+    assert!(grammar.parse("a(x: min[y], z: max[y]) :- b(y).").is_err());
+    assert!(grammar.parse("a(x: percentile[y, 0.5]) :- b(y).").is_err());
+}
+
+#[test]
+fn parse_list_and_record() {
+    let grammar = Grammar::new();
+    let result = grammar.parse(r#"names(name) :- person(info: {name, tags: [1, "a"]})."#);
+    let prog = result.unwrap();
+    assert_eq!(
+        prog.rules[0].clauses,
+        vec![Clause::Fact(Fact {
+            name: "person".into(),
+            props: btreemap! {
+                "info".into() => Value::Record(btreemap! {
+                    "name".into() => Value::Id("name".into()),
+                    "tags".into() => Value::List(vec![
+                        Value::Literal(Literal::Number("1".into())),
+                        Value::Literal(Literal::String("a".into())),
+                    ]),
+                }),
+            },
+        })],
+    );
+
+    assert!(grammar.parse("empty(list: [], record: {}).").is_ok());
+    assert!(grammar.parse("bad(record: {null}).").is_err());
+}
+
+#[test]
+fn parse_null() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse("missing(name) :- person(name, email: null).")
+        .unwrap();
+    assert_eq!(
+        prog.rules[0].clauses,
+        vec![Clause::Fact(Fact {
+            name: "person".into(),
+            props: btreemap! {
+                "email".into() => Value::Literal(Literal::Null),
+                "name".into() => Value::Id("name".into()),
+            },
+        })],
+    );
+    assert!(grammar.parse("bad(null).").is_err());
+}
+
+#[test]
+fn parse_disjunction() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse("link(x, y) :- (edge(x, y); edge(x: y, y: x), `x != y`).")
+        .unwrap();
+    let edge = |x: &str, y: &str| {
+        Clause::Fact(Fact {
+            name: "edge".into(),
+            props: btreemap! {
+                "x".into() => Value::Id(x.into()),
+                "y".into() => Value::Id(y.into()),
+            },
+        })
+    };
+    assert_eq!(
+        prog.rules[0].clauses,
+        vec![Clause::Disjunction(vec![
+            vec![edge("x", "y")],
+            vec![edge("y", "x"), Clause::Expr("x != y".into())],
+        ])],
+    );
+
+    let rules = prog.rules[0].expand();
+    assert_eq!(rules.len(), 2);
+    assert_eq!(rules[0].clauses, vec![edge("x", "y")]);
+    assert_eq!(rules[1].clauses.len(), 2);
+
+    let prog = grammar
+        .parse("p(x) :- (a(x); b(x)), (c(x); d(x); e(x)).")
+        .unwrap();
+    assert_eq!(prog.rules[0].expand().len(), 6);
+}
+
+#[test]
+fn parse_native_expressions() {
+    let grammar = Grammar::new();
+    let id = |name: &str| Box::new(Value::Id(name.into()));
+    let num = |n: &str| Box::new(Value::Literal(Literal::Number(n.into())));
+
+    let prog = grammar
+        .parse("p(z) :- q(x, y), z = x + y * -2, x-1 < abs(y) || !ok(x).")
+        .unwrap();
+    assert_eq!(
+        prog.rules[0].clauses[1],
+        Clause::Binding(
+            "z".into(),
+            Value::Binary(
+                BinaryOp::Add,
+                id("x"),
+                Box::new(Value::Binary(BinaryOp::Mul, id("y"), num("-2"))),
+            ),
+        ),
+    );
+    assert_eq!(
+        prog.rules[0].clauses[2],
+        Clause::Condition(Value::Binary(
+            BinaryOp::Or,
+            Box::new(Value::Binary(
+                BinaryOp::Lt,
+                Box::new(Value::Binary(BinaryOp::Sub, id("x"), num("1"))),
+                Box::new(Value::Call("abs".into(), vec![Value::Id("y".into())])),
+            )),
+            Box::new(Value::Unary(
+                UnaryOp::Not,
+                Box::new(Value::Call("ok".into(), vec![Value::Id("x".into())])),
+            )),
+        )),
+    );
+
+    // Without an operator, a call in a rule body is still a fact.
+    let prog = grammar.parse("p(x) :- q(x), (x == 1; x > 3).").unwrap();
+    assert!(matches!(prog.rules[0].clauses[0], Clause::Fact(_)));
+    assert!(matches!(
+        &prog.rules[0].clauses[1],
+        Clause::Disjunction(branches) if matches!(branches[1][0], Clause::Condition(_)),
+    ));
+
+    let prog = grammar
+        .parse("total(n: count[x] { q(x) } + 1, m: (1 + 2) * 3).")
+        .unwrap();
+    assert!(matches!(
+        &prog.rules[0].goal.props["n"],
+        Value::Binary(BinaryOp::Add, lhs, _) if matches!(**lhs, Value::Aggregate(_)),
+    ));
+    assert!(matches!(
+        &prog.rules[0].goal.props["m"],
+        Value::Binary(BinaryOp::Mul, lhs, _) if matches!(**lhs, Value::Binary(BinaryOp::Add, ..)),
+    ));
+
+    assert!(grammar.parse("p(x) :- q(x), 1 < x < 3.").is_err());
+}
+
+#[test]
+fn parse_single_value() {
+    let grammar = Grammar::new();
+    assert_eq!(
+        grammar.parse_value("-x"),
+        Ok(Value::Unary(UnaryOp::Neg, Box::new(Value::Id("x".into())))),
+    );
+    assert_eq!(
+        grammar.parse_value("+5"),
+        Ok(Value::Literal(Literal::Number("5".into()))),
+    );
+    assert_eq!(
+        grammar.parse_value("+x"),
+        Ok(Value::Unary(UnaryOp::Plus, Box::new(Value::Id("x".into())))),
+    );
+    assert!(grammar.parse("p(x: +5).").is_ok());
+    assert!(grammar.parse_value("x + 1").is_ok());
+    assert!(grammar.parse_value("Math.sqrt(x)").is_err());
+    assert!(grammar.parse_value("a(x).").is_err());
+}
+
+#[test]
+fn parse_import_format() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+import cars from "https://example.com/cars.txt" as csv(delimiter: ";", types: {mpg: "number"})
+import logs from "https://example.com/logs" as jsonl
+"#,
+        )
+        .unwrap();
+    assert_eq!(
+        prog.imports[0].format,
+        Some(ImportFormat {
+            name: "csv".into(),
+            options: btreemap! {
+                "delimiter".into() => Value::Literal(Literal::String(";".into())),
+                "types".into() => Value::Record(btreemap! {
+                    "mpg".into() => Value::Literal(Literal::String("number".into())),
+                }),
+            },
+        }),
+    );
+    assert_eq!(
+        prog.imports[1].format,
+        Some(ImportFormat {
+            name: "jsonl".into(),
+            options: Default::default(),
+        }),
+    );
+    assert!(grammar.parse(r#"import x from "a.csv" as"#).is_err());
+}
+
+#[test]
+fn parse_import_fields() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"import cars(name: Name, mpg: "Miles per Gallon", year) from "gh://vega/cars.json""#,
+        )
+        .unwrap();
+    assert_eq!(
+        prog.imports[0].fields,
+        Some(btreemap! {
+            "name".into() => "Name".into(),
+            "mpg".into() => "Miles per Gallon".into(),
+            "year".into() => "year".into(),
+        }),
+    );
+    assert!(grammar
+        .parse(r#"import cars() from "gh://vega/cars.json""#)
+        .is_err());
+    assert!(grammar
+        .parse(r#"import cars(mpg: 3) from "gh://vega/cars.json""#)
+        .is_err());
+}
+
+#[test]
+fn parse_import_sha256() {
+    let grammar = Grammar::new();
+    let hash = "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+    let prog = grammar
+        .parse(&format!(
+            r#"import x from "https://example.com/x.csv" sha256 "{}" as csv"#,
+            hash,
+        ))
+        .unwrap();
+    assert_eq!(prog.imports[0].sha256, Some(hash.to_lowercase()));
+    assert_eq!(prog.imports[0].format.as_ref().unwrap().name, "csv");
+    assert!(grammar
+        .parse(r#"import x from "https://example.com/x.csv" sha256 "abc123""#)
+        .is_err());
+}
+
+#[test]
+fn parse_visibility() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+export import cars from "npm://vega-datasets/data/cars.json"
+private edge(x: 1, y: 2).
+export path(x, y) :- edge(x, y).
+path(x, z) :- path(x, y), edge(x: y, y: z).
+private(value: 3).
+"#,
+        )
+        .unwrap();
+    assert_eq!(prog.rules.len(), 4);
+    assert_eq!(prog.exports, btreeset! {"cars".into(), "path".into()});
+    assert_eq!(prog.private, btreeset! {"edge".into()});
+    assert_eq!(prog.results(), btreeset! {"cars".into(), "path".into()});
+    assert!(grammar.parse("export aggregate first `x => x[0]`").is_err());
+}
+
+#[test]
+fn parse_module_uses() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+use "lib/graph.pcv"
+use "lib/graph.pcv" as g
+use(x: 1).
+reach(x, y) :- graph.path(x, y), g.path(x: y, y: x).
+"#,
+        )
+        .unwrap();
+    assert_eq!(
+        prog.uses,
+        vec![
+            Use {
+                path: "lib/graph.pcv".into(),
+                alias: None,
+            },
+            Use {
+                path: "lib/graph.pcv".into(),
+                alias: Some("g".into()),
+            },
+        ],
+    );
+    assert_eq!(prog.relations(), btreeset! {"use".into(), "reach".into()});
+    assert_eq!(
+        prog.deps(),
+        btreeset! {"graph.path".into(), "g.path".into()}
+    );
+}
+
+#[test]
+fn parse_templates() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+export closure!(follows, reach).
+template closure(E, R) {
+    R(x, y) :- E(x, y).
+    R(x, y) :- R(x, y: z), E(x: z, y).
+}
+closure!(graph.edge, path).
+"#,
+        )
+        .unwrap();
+    assert_eq!(prog.rules.len(), 4);
+    assert_eq!(prog.relations(), btreeset! {"reach".into(), "path".into()});
+    assert_eq!(
+        prog.deps(),
+        btreeset! {"follows".into(), "graph.edge".into()}
+    );
+    assert_eq!(prog.exports, btreeset! {"reach".into()});
+    let expected = grammar
+        .parse("path(x, y) :- path(x, y: z), graph.edge(x: z, y).")
+        .unwrap()
+        .rules
+        .remove(0);
+    assert_eq!(prog.rules[3].goal, expected.goal);
+    assert_eq!(prog.rules[3].clauses, expected.clauses);
+    let expansion = prog.rules[3].origin.expansion.as_ref().unwrap();
+    assert_eq!(expansion.template, "closure");
+    assert!(expansion.declaration.start < expansion.declaration.end);
+    assert!(expansion.declaration.end <= expansion.instance.start);
+
+    // Each instance gets its own copy of relations that are not parameters.
+    let prog = grammar
+        .parse(
+            r#"
+template close(E, R) {
+    step(x, y) :- E(x, y).
+    R(x, y) :- step(x, y).
+}
+export close!(a, b).
+close!(c, d).
+"#,
+        )
+        .unwrap();
+    assert_eq!(
+        prog.relations(),
+        btreeset! {"b".into(), "d".into(), "close!1_step".into(), "close!2_step".into()},
+    );
+    assert_eq!(prog.results(), btreeset! {"b".into()});
+    assert_eq!(prog.deps(), btreeset! {"a".into(), "c".into()},);
+
+    let template = "template t(A) { A(x: 1). }\n";
+    assert!(grammar.parse(&format!("{}t!(a).", template)).is_ok());
+    assert!(grammar.parse(&format!("{}t!(a, b).", template)).is_err());
+    assert!(grammar.parse(&format!("{}u!(a).", template)).is_err());
+    assert!(grammar.parse(&format!("{}{}", template, template)).is_err());
+    assert!(grammar.parse("template t(A, A) { A(x: 1). }").is_err());
+}
+
+#[test]
+fn parse_asserts() {
+    let grammar = Grammar::new();
+    let prog = grammar
+        .parse(
+            r#"
+assert count[1] { bad(x) } = 0.
+test "tc is transitive" {
+    edge(x: 1, y: 2).
+    assert max[y] { tc(x: 1, y) } = 2.
+}
+test(name: "not a test block").
+"#,
+        )
+        .unwrap();
+    let count_bad = grammar.parse_value("count[1] { bad(x) }").unwrap();
+    assert_eq!(
+        prog.asserts,
+        vec![Assert {
+            left: count_bad,
+            right: Value::Literal(Literal::Number("0".into())),
+        }],
+    );
+    assert_eq!(prog.tests.len(), 1);
+    assert_eq!(prog.tests[0].name, "tc is transitive");
+    assert_eq!(prog.tests[0].rules.len(), 1);
+    assert_eq!(prog.tests[0].asserts.len(), 1);
+    assert_eq!(prog.relations(), btreeset! {"test".into()});
+
+    assert!(grammar.parse("assert count[1] { bad(x) }.").is_err());
+    assert!(grammar.parse("test { assert 1 = 1. }").is_err());
+    assert!(grammar
+        .parse(r#"test "t" { import x from "a.json" }"#)
+        .is_err());
+}
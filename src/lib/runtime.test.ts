@@ -1,5 +1,5 @@
 import { expect } from "chai";
-import init from "percival-wasm";
+import init, { CompileOptions } from "percival-wasm";
 import { build } from "./runtime";
 
 async function checkProgram({
@@ -36,6 +36,20 @@ describe("basic compilation", () => {
     expect(build("tc(x:).").ok).to.be.false;
   });
 
+  it("passes compile options to the compiler", async () => {
+    await init();
+    const options = new CompileOptions();
+    options.set_backend("native");
+    const result = build("tc(x: 3).", options);
+    expect(result.ok).to.be.true;
+    if (!result.ok) throw null;
+    expect(await result.evaluate({})).to.deep.equal({ tc: [{ x: 3 }] });
+
+    const sandboxed = new CompileOptions();
+    sandboxed.set_sandbox(true);
+    expect(build("tc(x: `typeof 3`).", sandboxed).ok).to.be.false;
+  });
+
   it("evaluates a simple program", async () => {
     await init();
     const result = build("tc(x: 3).");
@@ -71,6 +85,20 @@ tc(x, y) :- tc(x, y: z), edge(x: z, y).
     });
   });
 
+  it("only outputs visible relations", async () => {
+    await init();
+    const result = build(`
+private edge(x: 1, y: 2).
+private edge(x: 2, y: 3).
+tc(x, y) :- edge(x, y).
+tc(x, y) :- tc(x, y: z), edge(x: z, y).
+`);
+    expect(result.ok).to.be.true;
+    if (!result.ok) throw null; // unreachable
+    expect(result.results).to.have.members(["tc"]);
+    expect(Object.keys(await result.evaluate({}))).to.have.members(["tc"]);
+  });
+
   it("evaluates a bigger transitive closure", async () => {
     await init();
     await checkProgram({
@@ -224,6 +252,74 @@ describe("promise cancellation", () => {
   });
 });
 
+describe("resource limits", () => {
+  async function checkLimit(
+    src: string,
+    input: Record<string, object[]>,
+    options: CompileOptions,
+    message: string,
+  ) {
+    const result = build(src, options);
+    expect(result.ok).to.be.true;
+    if (!result.ok) throw null; // unreachable
+    try {
+      await result.evaluate(input);
+      throw new Error("Promise should have thrown");
+    } catch (error: any) {
+      expect(error.message).to.include(message);
+    }
+  }
+
+  const counter = "n(x: 0).\nn(x: `x + 1`) :- n(x).";
+  const range = (k: number) => [...Array(k).keys()].map((x) => ({ x }));
+
+  it("stops after too many iterations", async () => {
+    await init();
+    const options = new CompileOptions();
+    options.set_max_iterations(10);
+    await checkLimit(
+      counter,
+      {},
+      options,
+      'exceeded the iterations limit of 10, while computing relation "n"',
+    );
+  });
+
+  it("stops when a relation has too many tuples", async () => {
+    await init();
+    const options = new CompileOptions();
+    options.set_max_tuples(100);
+    await checkLimit(
+      counter,
+      {},
+      options,
+      'exceeded the tuples limit of 100, while computing relation "n"',
+    );
+
+    // A single iteration that derives too many tuples is also stopped.
+    const pairs = new CompileOptions();
+    pairs.set_max_tuples(1000);
+    await checkLimit(
+      "pair(x, y) :- n(x), n(x: y).",
+      { n: range(100) },
+      pairs,
+      'exceeded the tuples limit of 1000, while computing relation "pair"',
+    );
+  });
+
+  it("stops after running for too long", async () => {
+    await init();
+    const options = new CompileOptions();
+    options.set_time_limit(50);
+    await checkLimit(
+      "pair(x, y) :- n(x), n(x: y).",
+      { n: range(3000) },
+      options,
+      'exceeded the time limit of 50ms, while computing relation "pair"',
+    );
+  });
+});
+
 describe("import directives", () => {
   it("can load crimea.json", async () => {
     await init();
@@ -278,6 +374,21 @@ count(value: count[1] { iowa() }).
       },
     });
   });
+
+  it("rejects data that does not match its sha256 hash", async () => {
+    await init();
+    const result = build(`
+import crimea from "npm://vega-datasets@2.1.0/data/crimea.json" sha256 "${"0".repeat(64)}"
+`);
+    expect(result.ok).to.be.true;
+    if (!result.ok) throw null; // unreachable
+    try {
+      await result.evaluate({});
+      throw new Error("Promise should have thrown");
+    } catch (error: any) {
+      expect(error.message).to.match(/^Integrity check failed/);
+    }
+  });
 });
 
 describe("aggregation operators", () => {
@@ -404,4 +515,273 @@ yearly_mpg(year, value) :-
       },
     });
   });
+
+  it("evaluates statistical and row aggregates", async () => {
+    await init();
+    await checkProgram({
+      src: `
+stats(
+  distinct: count_distinct[name] { num(name) },
+  median: median[x] { num(x) },
+  top: percentile[x, 1] { num(x) },
+  names: string_agg[name, "-"] { num(x: 1, name) },
+  best: argmax[x] { num(x, name) },
+  positive: all[\`x > 0\`] { num(x) }
+).
+`,
+      deps: ["num"],
+      results: ["stats"],
+      input: {
+        num: [
+          { x: 1, name: "a" },
+          { x: 2, name: "b" },
+          { x: 4, name: "c" },
+          { x: 3, name: "c" },
+        ],
+      },
+      output: {
+        stats: [
+          {
+            distinct: 3,
+            median: 2.5,
+            top: 4,
+            names: "a",
+            best: { x: 4, name: "c" },
+            positive: true,
+          },
+        ],
+      },
+    });
+  });
+
+  it("aggregates over relations computed earlier in the cell", async () => {
+    await init();
+    await checkProgram({
+      src: `
+tc(x, y) :- edge(x, y).
+tc(x, y: z) :- tc(x, y), edge(x: y, y: z).
+reach(x, n: count[y] { tc(x, y) }) :- node(x).
+`,
+      deps: ["edge", "node"],
+      results: ["tc", "reach"],
+      input: {
+        edge: [
+          { x: 1, y: 2 },
+          { x: 2, y: 3 },
+        ],
+        node: [{ x: 1 }, { x: 2 }, { x: 3 }],
+      },
+      output: {
+        tc: [
+          { x: 1, y: 2 },
+          { x: 1, y: 3 },
+          { x: 2, y: 3 },
+        ],
+        reach: [
+          { x: 1, n: 2 },
+          { x: 2, n: 1 },
+          { x: 3, n: 0 },
+        ],
+      },
+    });
+  });
+
+  it("computes shortest paths with a min lattice", async () => {
+    await init();
+    await checkProgram({
+      src: `
+dist(x, d: min[0]) :- source(x).
+dist(x: y, d: min[\`d + w\`]) :- dist(x, d), edge(x, y, w).
+far(x) :- dist(x, d), \`d > 4\`.
+`,
+      deps: ["source", "edge"],
+      results: ["dist", "far"],
+      input: {
+        source: [{ x: "a" }],
+        edge: [
+          { x: "a", y: "b", w: 5 },
+          { x: "a", y: "c", w: 1 },
+          { x: "c", y: "b", w: 1 },
+          { x: "b", y: "d", w: 10 },
+          { x: "d", y: "a", w: 1 },
+        ],
+      },
+      output: {
+        dist: [
+          { x: "a", d: 0 },
+          { x: "b", d: 2 },
+          { x: "c", d: 1 },
+          { x: "d", d: 12 },
+        ],
+        far: [{ x: "d" }],
+      },
+    });
+  });
+
+  it("matches and deduplicates lists and records", async () => {
+    await init();
+    await checkProgram({
+      src: `
+names(name, first) :- person(info: {name, tags: [first, _second]}).
+pairs(p: [x, y]) :- person(info: {tags: [x, y]}).
+`,
+      deps: ["person"],
+      results: ["names", "pairs"],
+      input: {
+        person: [
+          { info: { name: "ann", tags: ["a", "b"] } },
+          { info: { name: "bob", tags: ["a", "b"] } },
+          { info: { name: "cat", tags: ["c"] } },
+        ],
+      },
+      output: {
+        names: [
+          { name: "ann", first: "a" },
+          { name: "bob", first: "a" },
+        ],
+        pairs: [{ p: ["a", "b"] }],
+      },
+    });
+  });
+
+  it("keeps non-finite numbers distinct in the native backend", async () => {
+    await init();
+    const options = new CompileOptions();
+    options.set_backend("native");
+    const result = build("v(x) :- n(x).", options);
+    expect(result.ok).to.be.true;
+    if (!result.ok) throw null; // unreachable
+    const observed = await result.evaluate({
+      n: [{ x: null }, { x: NaN }, { x: Infinity }, { x: -Infinity }, { x: NaN }],
+    });
+    expect(observed.v).to.have.deep.members([
+      { x: null },
+      { x: NaN },
+      { x: Infinity },
+      { x: -Infinity },
+    ]);
+  });
+
+  it("treats missing fields as null, which never joins", async () => {
+    await init();
+    await checkProgram({
+      src: `
+missing(name) :- person(name, email: null).
+shared(name, other) :- person(name, email), person(name: other, email).
+`,
+      deps: ["person"],
+      results: ["missing", "shared"],
+      input: {
+        person: [
+          { name: "a", email: "x" },
+          { name: "b" },
+          { name: "c", email: null },
+        ],
+      },
+      output: {
+        missing: [{ name: "b" }, { name: "c" }],
+        shared: [{ name: "a", other: "a" }],
+      },
+    });
+  });
+
+  it("evaluates disjunctions as separate rules", async () => {
+    await init();
+    await checkProgram({
+      src: `
+link(x, y) :- (edge(x, y); edge(x: y, y: x)).
+degree(x: v, n: count[1] { (link(x: v, y); loop(x: v), y = v) }) :- vertex(v).
+both(n: count[1] { (edge(x); loop(x)) }).
+`,
+      deps: ["edge", "loop", "vertex"],
+      results: ["both", "degree", "link"],
+      input: {
+        edge: [{ x: 1, y: 2 }],
+        loop: [{ x: 1 }],
+        vertex: [{ v: 1 }, { v: 2 }],
+      },
+      output: {
+        both: [{ n: 1 }],
+        degree: [
+          { x: 1, n: 2 },
+          { x: 2, n: 1 },
+        ],
+        link: [
+          { x: 1, y: 2 },
+          { x: 2, y: 1 },
+        ],
+      },
+    });
+  });
+
+  it("evaluates native expressions and functions", async () => {
+    await init();
+    await checkProgram({
+      src: `
+n(x: 0).
+n(x: y) :- n(x), y = x + 1, y < 5.
+big(x, label: "n=" + string(x), sq: pow(x, 2)) :- n(x), x >= 3 && x != 4.
+`,
+      deps: [],
+      results: ["big", "n"],
+      input: {},
+      output: {
+        big: [{ x: 3, label: "n=3", sq: 9 }],
+        n: [{ x: 0 }, { x: 1 }, { x: 2 }, { x: 3 }, { x: 4 }],
+      },
+    });
+  });
+
+  it("propagates nulls through native expressions", async () => {
+    await init();
+    await checkProgram({
+      src: `
+total(x, s: x + y, n: -y) :- p(x, y).
+big(x) :- p(x, y), y > 1 || y != 2.
+same(x) :- p(x, y), q(z), y == z.
+`,
+      deps: ["p", "q"],
+      results: ["big", "same", "total"],
+      input: {
+        p: [{ x: 1, y: 2 }, { x: 2, y: null }, { x: 3 }],
+        q: [{ z: 2 }, { z: null }],
+      },
+      output: {
+        big: [{ x: 1 }],
+        same: [{ x: 1 }],
+        total: [
+          { x: 1, s: 3, n: -2 },
+          { x: 2, s: null, n: null },
+          { x: 3, s: null, n: null },
+        ],
+      },
+    });
+  });
+
+  it("calls built-in string and date functions", async () => {
+    await init();
+    await checkProgram({
+      src: `
+info(name: lower(name), year: year(born), day: day(parse_date(born))) :-
+  person(name, born),
+  starts_with(name, "A") || regex_match(name, "^b.*b$"),
+  !contains(name, "z").
+`,
+      deps: ["person"],
+      results: ["info"],
+      input: {
+        person: [
+          { name: "Ann-Lee", born: "2021-03-04T05:06:07Z" },
+          { name: "bob", born: "1999-12-31" },
+          { name: "Az", born: "2000-01-01" },
+        ],
+      },
+      output: {
+        info: [
+          { name: "ann-lee", year: 2021, day: 4 },
+          { name: "bob", year: 1999, day: 31 },
+        ],
+      },
+    });
+  });
 });
@@ -1,4 +1,10 @@
-import { compile } from "percival-wasm";
+import {
+  compile,
+  compile_with,
+  CompileOptions,
+  runtime_aggregates,
+  runtime_functions,
+} from "percival-wasm";
 import Worker from "./runtime.worker?worker";
 
 interface CancellablePromise<T> extends Promise<T> {
@@ -21,10 +27,12 @@ type CompilerResultErr = {
 
 export type CompilerResult = CompilerResultOk | CompilerResultErr;
 
-export function build(src: string): CompilerResult {
-  let result = compile(src);
+export function build(src: string, options?: CompileOptions): CompilerResult {
+  let result = options ? compile_with(src, options) : compile(src);
   if (result.is_ok()) {
     const code = result.js();
+    const aggregates = runtime_aggregates();
+    const functions = runtime_functions();
     return {
       ok: true,
       evaluate: (deps) => {
@@ -40,7 +48,7 @@ export function build(src: string): CompilerResult {
             reject(new Error(event.message));
             worker.terminate();
           });
-          worker.postMessage({ type: "source", code });
+          worker.postMessage({ type: "source", code, aggregates, functions });
           worker.postMessage({ type: "eval", deps });
         });
         promise.cancel = () => {
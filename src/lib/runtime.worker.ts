@@ -1,60 +1,151 @@
 import Immutable from "immutable";
-import { autoType, csvParse, tsvParse } from "d3-dsv";
+import { autoType, dsvFormat } from "d3-dsv";
+
+type Format = "json" | "jsonl" | "csv" | "tsv" | "columnar";
+
+/** Options for parsing a dataset, from the `as` clause of an import. */
+interface LoadOptions {
+  format?: Format;
+  delimiter?: string;
+  header?: boolean;
+  types?: Record<string, "auto" | "string" | "number" | "boolean" | "date">;
+  sha256?: string;
+}
 
 /** Load data from an external source. */
-async function load(url: string): Promise<object[]> {
+async function load(
+  url: string,
+  options: LoadOptions = {},
+): Promise<object[]> {
   const resp = await fetch(url);
   if (!resp.ok) {
     throw new Error(`Failed to fetch ${url}:\n${await resp.text()}`);
   }
-  const contentType = resp.headers.get("Content-Type");
-  if (url.endsWith(".json") || contentType?.match(/application\/json/i)) {
-    return resp.json();
-  } else if (url.endsWith(".csv") || contentType?.match(/text\/csv/i)) {
-    return csvParse(await resp.text(), autoType);
+  const data = await resp.arrayBuffer();
+  if (options.sha256) {
+    await checkIntegrity(url, data, options.sha256);
+  }
+  const text = new TextDecoder().decode(data);
+  const format =
+    options.format ?? inferFormat(url, resp.headers.get("Content-Type"));
+  switch (format) {
+    case "json":
+      return JSON.parse(text);
+    case "jsonl":
+      return text
+        .split("\n")
+        .filter((line) => line.trim())
+        .map((line) => JSON.parse(line));
+    case "columnar":
+      return fromColumns(JSON.parse(text));
+    case "csv":
+    case "tsv":
+      return parseDsv(text, format === "csv" ? "," : "\t", options);
+  }
+}
+
+/** Check that a dataset matches the hash given in its import. */
+async function checkIntegrity(url: string, data: ArrayBuffer, sha256: string) {
+  const digest = await crypto.subtle.digest("SHA-256", data);
+  const actual = Array.from(new Uint8Array(digest))
+    .map((byte) => byte.toString(16).padStart(2, "0"))
+    .join("");
+  if (actual !== sha256) {
+    const source = url.startsWith("data:") ? "inline data" : url;
+    throw new Error(
+      `Integrity check failed for ${source}: expected SHA-256 ${sha256}, but
+the data has hash ${actual}. The dataset may have changed since the import's
+hash was recorded.`,
+    );
+  }
+}
+
+/** Guess the format of a dataset from its URL or MIME type. */
+function inferFormat(url: string, contentType: string | null): Format {
+  const path = url.split(/[?#]/)[0];
+  if (path.endsWith(".json") || contentType?.match(/application\/json/i)) {
+    return "json";
   } else if (
-    url.endsWith(".tsv") ||
+    path.match(/\.(jsonl|ndjson)$/) ||
+    contentType?.match(/application\/(x-)?(ndjson|jsonl)/i)
+  ) {
+    return "jsonl";
+  } else if (path.endsWith(".csv") || contentType?.match(/text\/csv/i)) {
+    return "csv";
+  } else if (
+    path.endsWith(".tsv") ||
     contentType?.match(/text\/tab-separated-values/i)
   ) {
-    return tsvParse(await resp.text(), autoType);
+    return "tsv";
   } else {
     throw new Error(
-      `Unknown file format for ${url}. Only JSON, CSV, and TSV are supported.
-Try adding a file extension to the URL or providing a MIME Content-Type header.`,
+      `Unknown file format for ${url}. Only JSON, JSONL, CSV, and TSV are supported.
+Try adding a file extension to the URL, providing a MIME Content-Type header, or
+specifying the format in the import, like \`import data from "..." as csv\`.`,
     );
   }
 }
 
-/** Implementations of aggregates. Keep this in sync with `codegen.rs`. */
-const aggregates: Record<string, (results: any[]) => any> = {
-  count(results) {
-    return results.length;
-  },
-  sum(results) {
-    return results.reduce((x, y) => x + y, 0);
-  },
-  mean(results) {
-    return results.reduce((x, y) => x + y, 0) / results.length;
-  },
-  min(results) {
-    let min = null;
-    for (const x of results) {
-      if (min === null || x < min) {
-        min = x;
-      }
+/** Convert an object of equal-length column arrays into rows. */
+function fromColumns(data: Record<string, unknown[]>): object[] {
+  const columns = Object.keys(data);
+  const length = columns.length ? data[columns[0]].length : 0;
+  for (const column of columns) {
+    if (!Array.isArray(data[column]) || data[column].length !== length) {
+      throw new Error(`Column "${column}" does not have ${length} values`);
     }
-    return min;
-  },
-  max(results) {
-    let max = null;
-    for (const x of results) {
-      if (max === null || x > max) {
-        max = x;
-      }
+  }
+  return Array.from({ length }, (_, i) =>
+    Object.fromEntries(columns.map((column) => [column, data[column][i]])),
+  );
+}
+
+/** Parse delimiter-separated values, with optional types for each column. */
+function parseDsv(
+  text: string,
+  delimiter: string,
+  options: LoadOptions,
+): object[] {
+  const dsv = dsvFormat(options.delimiter ?? delimiter);
+  const rows: Record<string, string>[] =
+    options.header === false
+      ? dsv
+          .parseRows(text)
+          .map((row) =>
+            Object.fromEntries(
+              row.map((value, i) => [`column${i + 1}`, value]),
+            ),
+          )
+      : dsv.parse(text);
+  const types = Object.entries(options.types ?? {});
+  return rows.map((row) => {
+    const result: Record<string, unknown> = autoType({ ...row });
+    for (const [column, type] of types) {
+      result[column] = convertValue(row[column], type);
     }
-    return max;
-  },
-};
+    return result;
+  });
+}
+
+function convertValue(value: string | undefined, type: string): unknown {
+  if (value === undefined || value === "") {
+    return null;
+  }
+  switch (type) {
+    case "string":
+      return value;
+    case "number":
+      return Number(value);
+    case "boolean":
+      return value.toLowerCase() === "true";
+    case "date": {
+      const time = Date.parse(value);
+      return Number.isNaN(time) ? null : time;
+    }
+    default:
+      return autoType({ value }).value;
+  }
+}
 
 const AsyncFunction = Object.getPrototypeOf(async function () {}).constructor;
 
@@ -62,18 +153,21 @@ let evaluate:
   | undefined
   | ((deps: Record<string, object[]>) => Promise<Record<string, object[]>>);
 
-function initialize(js: string) {
+function initialize(js: string, aggregatesJs: string, functionsJs: string) {
   if (evaluate) {
     throw new Error("internal: worker was already initialized");
   }
+  // Implementations of aggregates and functions, generated from `runtime.rs`.
+  const aggregates = new Function(`return ${aggregatesJs};`)();
+  const functions = new Function(`return ${functionsJs};`)();
   const fn = new AsyncFunction("__percival_deps", "__percival", js);
   evaluate = (deps: Record<string, object[]>) =>
-    fn(deps, { Immutable, load, aggregates });
+    fn(deps, { Immutable, load, aggregates, functions });
 }
 
 onmessage = (event) => {
   if (event.data.type === "source") {
-    initialize(event.data.code);
+    initialize(event.data.code, event.data.aggregates, event.data.functions);
   } else if (event.data.type === "eval") {
     if (!evaluate) {
       throw new Error("internal: worker was not initialized");